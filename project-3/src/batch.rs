@@ -0,0 +1,101 @@
+//! Groups of writes that are applied all at once
+
+use std::collections::HashMap;
+
+use crate::{KvsError, MPCommand, Result};
+
+/// Sets and removals that `KvsEngine::write_batch` applies in order, either
+/// all of them or none
+///
+/// Removing a key that is not set, before the batch or by an earlier write of
+/// the batch, fails the whole batch with `KvsError::KeyNotFound`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    commands: Vec<MPCommand>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Adds a set of `key` to `value`
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.commands.push(MPCommand::Set {
+            key: key.into(),
+            value: value.into(),
+            expires_at: None,
+        });
+        self
+    }
+
+    /// Adds a removal of `key`
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.commands.push(MPCommand::Rm { key: key.into() });
+        self
+    }
+
+    /// Returns the writes of the batch, all of them `Set` or `Rm` commands
+    pub fn commands(&self) -> &[MPCommand] {
+        &self.commands
+    }
+
+    /// Returns the number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Returns true if the batch holds no writes
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Builds a batch out of commands received over the wire
+    pub(crate) fn from_commands(commands: &[MPCommand]) -> Result<Self> {
+        for command in commands {
+            match command {
+                MPCommand::Set {
+                    expires_at: None, ..
+                }
+                | MPCommand::Rm { .. } => (),
+                _ => {
+                    return Err(KvsError::Protocol(
+                        "a batch may only hold sets and removals".to_owned(),
+                    ))
+                }
+            }
+        }
+        Ok(WriteBatch {
+            commands: commands.to_vec(),
+        })
+    }
+
+    /// Checks that every removal finds its key set, asking `is_set` about
+    /// keys the batch has not written yet
+    pub(crate) fn removals_find_keys<E>(
+        &self,
+        mut is_set: impl FnMut(&[u8]) -> std::result::Result<bool, E>,
+    ) -> std::result::Result<bool, E> {
+        let mut written: HashMap<&[u8], bool> = HashMap::new();
+        for command in &self.commands {
+            match command {
+                MPCommand::Set { key, .. } => {
+                    written.insert(key, true);
+                }
+                MPCommand::Rm { key } => {
+                    let set = match written.get(key.as_slice()) {
+                        Some(set) => *set,
+                        None => is_set(key)?,
+                    };
+                    if !set {
+                        return Ok(false);
+                    }
+                    written.insert(key, false);
+                }
+                _ => (),
+            }
+        }
+        Ok(true)
+    }
+}
@@ -1,18 +1,17 @@
-use std::io::{Read, Write};
-use std::mem::size_of;
-use std::net::{AddrParseError, SocketAddr, TcpStream};
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{crate_authors, crate_version};
-use kvs::MPCommand;
-use rmp_serde::Serializer;
-use serde::Serialize;
+use base64::Engine;
+use clap::crate_version;
+use kvs::{KvsClient, KvsError, Result};
 use structopt::StructOpt;
 
-const SIZE_OF_U64: usize = size_of::<u64>() as usize;
-
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(about = "key value store")]
-#[structopt(author = crate_authors!("\n"))]
+#[structopt(author = env!("CARGO_PKG_AUTHORS"))]
 #[structopt(version = crate_version!())]
 enum Kv {
     Get {
@@ -20,132 +19,390 @@ enum Kv {
         key: String,
         #[structopt(short, long, default_value = "127.0.0.1:4000")]
         addr: String,
+        /// write the raw value to this file instead of printing it
+        #[structopt(long, parse(from_os_str))]
+        file: Option<PathBuf>,
+        /// exit with status 2 if KEY is not set
+        #[structopt(long)]
+        exit_status: bool,
+        #[structopt(flatten)]
+        encoding: Encoding,
     },
     Set {
+        #[structopt(name = "KEY", index = 1)]
+        key: String,
+        #[structopt(name = "VALUE", index = 2, required_unless = "file")]
+        value: Option<String>,
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: String,
+        /// read the raw value from this file instead of taking VALUE
+        #[structopt(long, parse(from_os_str), conflicts_with = "VALUE")]
+        file: Option<PathBuf>,
+        /// expire the key after this many seconds
+        #[structopt(long)]
+        ttl: Option<u64>,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    Rm {
+        #[structopt(name = "KEY", index = 1)]
+        key: String,
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: String,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    /// Makes a key expire after SECONDS
+    Expire {
+        #[structopt(name = "KEY", index = 1)]
+        key: String,
+        #[structopt(name = "SECONDS", index = 2)]
+        seconds: u64,
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: String,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    /// Prints the seconds left until a key expires
+    Ttl {
+        #[structopt(name = "KEY", index = 1)]
+        key: String,
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: String,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    /// Stops a key from expiring
+    Persist {
+        #[structopt(name = "KEY", index = 1)]
+        key: String,
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: String,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    /// Swaps the value of KEY, exiting with status 2 if KEY does not hold the
+    /// expected value
+    Cas {
+        #[structopt(name = "KEY", index = 1)]
+        key: String,
+        /// value KEY must hold, leave out to require KEY to be absent
+        #[structopt(long)]
+        expected: Option<String>,
+        /// value to swap in, leave out to remove KEY
+        #[structopt(long)]
+        new: Option<String>,
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: String,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    /// Sets KEY to VALUE unless KEY is set, exiting with status 2 if it is
+    Setnx {
         #[structopt(name = "KEY", index = 1)]
         key: String,
         #[structopt(name = "VALUE", index = 2)]
         value: String,
         #[structopt(short, long, default_value = "127.0.0.1:4000")]
         addr: String,
+        #[structopt(flatten)]
+        encoding: Encoding,
     },
-    Rm {
+    /// Adds to the integer stored at KEY and prints the result
+    Incr {
         #[structopt(name = "KEY", index = 1)]
         key: String,
+        /// amount to add, negative to decrement
+        #[structopt(long, default_value = "1", allow_hyphen_values = true)]
+        by: i64,
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: String,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    /// Prints the values of several keys, one per line
+    Mget {
+        #[structopt(name = "KEY", required = true)]
+        keys: Vec<String>,
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: String,
+        /// exit with status 2 if any KEY is not set
+        #[structopt(long)]
+        exit_status: bool,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    /// Sets several keys at once, either all of them or none
+    Mset {
+        /// keys each followed by their value
+        #[structopt(name = "KEY VALUE", required = true, min_values = 2)]
+        args: Vec<String>,
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: String,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    /// Lists keys and their values in key order, starting from START
+    Scan {
+        #[structopt(name = "START", index = 1)]
+        start: Option<String>,
+        /// stop before this key
+        #[structopt(long)]
+        end: Option<String>,
+        /// list only the keys that start with this
+        #[structopt(long, conflicts_with_all = &["START", "end"])]
+        prefix: Option<String>,
+        /// list at most this many keys
+        #[structopt(short, long)]
+        limit: Option<usize>,
         #[structopt(short, long, default_value = "127.0.0.1:4000")]
         addr: String,
+        #[structopt(flatten)]
+        encoding: Encoding,
     },
 }
 
+/// How keys and values are written on the command line and printed
+#[derive(StructOpt, Debug, Clone)]
+struct Encoding {
+    /// keys and values are hex encoded
+    #[structopt(long, conflicts_with = "base64")]
+    hex: bool,
+    /// keys and values are base64 encoded
+    #[structopt(long)]
+    base64: bool,
+}
+
+impl Encoding {
+    fn decode(&self, arg: String) -> Result<Vec<u8>> {
+        if self.hex {
+            hex::decode(&arg).map_err(|err| invalid_arg(&arg, err))
+        } else if self.base64 {
+            base64::engine::general_purpose::STANDARD
+                .decode(&arg)
+                .map_err(|err| invalid_arg(&arg, err))
+        } else {
+            Ok(arg.into_bytes())
+        }
+    }
+
+    fn encode(&self, bytes: Vec<u8>) -> Vec<u8> {
+        if self.hex {
+            hex::encode(bytes).into_bytes()
+        } else if self.base64 {
+            base64::engine::general_purpose::STANDARD
+                .encode(bytes)
+                .into_bytes()
+        } else {
+            bytes
+        }
+    }
+}
+
+fn invalid_arg(arg: &str, err: impl std::fmt::Display) -> KvsError {
+    KvsError::InvalidConfig(format!("cannot decode {}: {}", arg, err))
+}
+
+impl Kv {
+    fn addr(&self) -> &str {
+        match self {
+            Kv::Get { addr, .. }
+            | Kv::Set { addr, .. }
+            | Kv::Rm { addr, .. }
+            | Kv::Expire { addr, .. }
+            | Kv::Ttl { addr, .. }
+            | Kv::Persist { addr, .. }
+            | Kv::Scan { addr, .. }
+            | Kv::Cas { addr, .. }
+            | Kv::Setnx { addr, .. }
+            | Kv::Incr { addr, .. }
+            | Kv::Mget { addr, .. }
+            | Kv::Mset { addr, .. } => addr,
+        }
+    }
+}
+
 fn main() {
     let opt = Kv::from_args();
 
-    let socket: SocketAddr = match opt.clone() {
-        Kv::Get { addr, key: _ } => {
-            let socket_parse: Result<SocketAddr, AddrParseError> = addr.parse();
-            match socket_parse {
-                Ok(socket) => socket,
-                Err(_err) => {
-                    std::process::exit(1);
+    let socket: SocketAddr = match opt.addr().parse() {
+        Ok(socket) => socket,
+        Err(_err) => {
+            std::process::exit(1);
+        }
+    };
+
+    match run(opt, socket) {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(CONDITION_FAILED),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Exit status of a conditional write whose condition did not hold, and of
+/// `get --exit-status` or `mget --exit-status` on a key that is not set
+const CONDITION_FAILED: i32 = 2;
+
+/// Runs the command, returning false if it was a conditional write that did
+/// not happen or a get or mget with `--exit-status` that found no value
+fn run(opt: Kv, socket: SocketAddr) -> Result<bool> {
+    let mut client = KvsClient::connect(socket)?;
+    match opt {
+        Kv::Get {
+            key,
+            file,
+            exit_status,
+            encoding,
+            ..
+        } => match client.get_bytes(&encoding.decode(key)?)? {
+            Some(value) => match file {
+                Some(path) => fs::write(path, value)?,
+                None => {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(&encoding.encode(value))?;
+                    stdout.write_all(b"\n")?;
                 }
+            },
+            None => {
+                println!("Key not found");
+                return Ok(!exit_status);
             }
-        }
+        },
         Kv::Set {
-            addr,
-            key: _,
-            value: _,
+            key,
+            value,
+            file,
+            ttl,
+            encoding,
+            ..
         } => {
-            let socket_parse: Result<SocketAddr, AddrParseError> = addr.parse();
-            match socket_parse {
-                Ok(socket) => socket,
-                Err(_err) => {
-                    std::process::exit(1);
+            let value = match (value, file) {
+                (_, Some(path)) => fs::read(path)?,
+                (Some(value), None) => encoding.decode(value)?,
+                (None, None) => unreachable!("VALUE is required without --file"),
+            };
+            let key = encoding.decode(key)?;
+            match ttl {
+                Some(seconds) => client.set_with_ttl(&key, &value, Duration::from_secs(seconds))?,
+                None => client.set_bytes(&key, &value)?,
+            }
+        }
+        Kv::Rm { key, encoding, .. } => client.remove_bytes(&encoding.decode(key)?)?,
+        Kv::Expire {
+            key,
+            seconds,
+            encoding,
+            ..
+        } => client.expire(&encoding.decode(key)?, Duration::from_secs(seconds))?,
+        Kv::Ttl { key, encoding, .. } => match client.ttl(&encoding.decode(key)?)? {
+            // round up so that a key with time left never shows 0
+            Some(ttl) => println!("{}", ttl.as_millis().div_ceil(1000)),
+            None => println!("No expiry"),
+        },
+        Kv::Persist { key, encoding, .. } => client.persist(&encoding.decode(key)?)?,
+        Kv::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            encoding,
+            ..
+        } => {
+            let pairs = match prefix {
+                Some(prefix) => client.scan_prefix(&encoding.decode(prefix)?, limit)?,
+                None => {
+                    let start = start.map_or(Ok(vec![]), |start| encoding.decode(start))?;
+                    let end = end.map(|end| encoding.decode(end)).transpose()?;
+                    client.scan(&start, end.as_deref(), limit)?
                 }
+            };
+            let mut stdout = io::stdout();
+            for (key, value) in pairs {
+                stdout.write_all(&encoding.encode(key))?;
+                stdout.write_all(b" ")?;
+                stdout.write_all(&encoding.encode(value))?;
+                stdout.write_all(b"\n")?;
             }
         }
-        Kv::Rm { addr, key: _ } => {
-            let socket_parse: Result<SocketAddr, AddrParseError> = addr.parse();
-            match socket_parse {
-                Ok(socket) => socket,
-                Err(_err) => {
-                    std::process::exit(1);
+        Kv::Mget {
+            keys,
+            exit_status,
+            encoding,
+            ..
+        } => {
+            let keys = keys
+                .into_iter()
+                .map(|key| encoding.decode(key))
+                .collect::<Result<Vec<_>>>()?;
+            let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+            let mut stdout = io::stdout();
+            let mut all_found = true;
+            for value in client.mget(&keys)? {
+                match value {
+                    Some(value) => stdout.write_all(&encoding.encode(value))?,
+                    None => {
+                        stdout.write_all(b"Key not found")?;
+                        all_found = false;
+                    }
                 }
+                stdout.write_all(b"\n")?;
             }
+            return Ok(all_found || !exit_status);
         }
-    };
-
-    let command: MPCommand = match opt {
-        Kv::Get { addr: _, key } => MPCommand::Get { key },
-        Kv::Set {
-            addr: _,
+        Kv::Mset { args, encoding, .. } => {
+            if args.len() % 2 != 0 {
+                return Err(KvsError::InvalidConfig(format!(
+                    "{} has no value",
+                    args[args.len() - 1]
+                )));
+            }
+            let args = args
+                .into_iter()
+                .map(|arg| encoding.decode(arg))
+                .collect::<Result<Vec<_>>>()?;
+            let pairs: Vec<(&[u8], &[u8])> = args
+                .chunks(2)
+                .map(|pair| (pair[0].as_slice(), pair[1].as_slice()))
+                .collect();
+            client.mset(&pairs)?;
+        }
+        Kv::Incr {
+            key, by, encoding, ..
+        } => println!("{}", client.incr_by(&encoding.decode(key)?, by)?),
+        Kv::Cas {
             key,
-            value,
-        } => MPCommand::Set { key, value },
-        Kv::Rm { addr: _, key } => MPCommand::Rm { key },
-    };
-
-    match TcpStream::connect(socket) {
-        Ok(mut stream) => {
-            stream.write(b"*").unwrap();
-            let num_commands = (1 as u64).to_be_bytes();
-            stream.write(&num_commands).unwrap();
-            let mut serialized_command = Vec::new();
-            command
-                .serialize(&mut Serializer::new(&mut serialized_command))
-                .unwrap();
-            let command_length = (serialized_command.len() as u64).to_be_bytes();
-            stream.write(&command_length).unwrap();
-            stream.write(&serialized_command).unwrap();
-
-            let mut start = [0 as u8; 1];
-            stream.read_exact(&mut start).unwrap();
-            match start[0] {
-                b'*' => {}
-                _ => {
-                    dbg!("incorrect initial byte");
-                }
+            expected,
+            new,
+            encoding,
+            ..
+        } => {
+            let expected = expected.map(|value| encoding.decode(value)).transpose()?;
+            let new = new.map(|value| encoding.decode(value)).transpose()?;
+            let swapped = client.compare_and_swap(
+                &encoding.decode(key)?,
+                expected.as_deref(),
+                new.as_deref(),
+            )?;
+            if !swapped {
+                eprintln!("Value does not match");
             }
-
-            let mut num_values = [0 as u8; SIZE_OF_U64];
-            stream.read_exact(&mut num_values).unwrap();
-            let num_values = u64::from_be_bytes(num_values);
-
-            assert_eq!(num_values, 1);
-
-            let mut error_code = [0 as u8; 1];
-            stream.read_exact(&mut error_code).unwrap();
-
-            let mut value_len = [0 as u8; SIZE_OF_U64];
-
-            match error_code[0] {
-                b'+' => {
-                    stream.read_exact(&mut value_len).unwrap();
-                    let value_len = u64::from_be_bytes(value_len);
-                    if value_len > 0 {
-                        let mut value: Vec<u8> = vec![0; value_len as usize];
-                        stream.read_exact(&mut value).unwrap();
-                        let value = std::str::from_utf8(&value).unwrap();
-                        println!("{}", value);
-                    }
-                    std::process::exit(0);
-                }
-                b'-' => {
-                    stream.read_exact(&mut value_len).unwrap();
-                    let value_len = u64::from_be_bytes(value_len);
-                    let mut value: Vec<u8> = vec![0; value_len as usize];
-                    stream.read_exact(&mut value).unwrap();
-                    let value = std::str::from_utf8(&value).unwrap();
-                    eprintln!("{}", value);
-                    std::process::exit(1);
-                }
-                _ => {
-                    dbg!("unexpected error code");
-                    std::process::exit(1);
-                }
-            };
+            return Ok(swapped);
         }
-        Err(_e) => {
-            std::process::exit(1);
+        Kv::Setnx {
+            key,
+            value,
+            encoding,
+            ..
+        } => {
+            let set = client.set_if_absent(&encoding.decode(key)?, &encoding.decode(value)?)?;
+            if !set {
+                eprintln!("Key already set");
+            }
+            return Ok(set);
         }
     }
+    Ok(true)
 }
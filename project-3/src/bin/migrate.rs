@@ -0,0 +1,57 @@
+use std::env::current_dir;
+use std::path::PathBuf;
+
+use clap::crate_version;
+use kvs::{migrate, FORMAT_VERSION};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(about = "Upgrades a kvs data directory to the current log format")]
+#[structopt(author = env!("CARGO_PKG_AUTHORS"))]
+#[structopt(version = crate_version!())]
+struct MigrateOpt {
+    /// data directory to upgrade, defaults to the current directory
+    #[structopt(name = "DIR", parse(from_os_str))]
+    dir: Option<PathBuf>,
+}
+
+fn main() {
+    let opt = MigrateOpt::from_args();
+    let dir = match opt.dir {
+        Some(dir) => dir,
+        None => current_dir().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1)
+        }),
+    };
+
+    let migration = migrate(&dir).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    });
+    if migration.is_empty() {
+        println!(
+            "{} is already at format version {}",
+            dir.display(),
+            FORMAT_VERSION
+        );
+        return;
+    }
+    if let Some(records) = migration.legacy_records {
+        println!(
+            "converted the single-file log into segment 0 ({} records)",
+            records
+        );
+    }
+    if !migration.upgraded_segments.is_empty() {
+        println!(
+            "added format headers to {} segments",
+            migration.upgraded_segments.len()
+        );
+    }
+    println!(
+        "{} is now at format version {}",
+        dir.display(),
+        FORMAT_VERSION
+    );
+}
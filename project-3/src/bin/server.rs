@@ -1,16 +1,14 @@
-use clap::{crate_authors, crate_version};
-use failure;
-use kvs::{KvStore, KvsEngine, MPCommand, SledEngine};
+use clap::crate_version;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    serve_async, Durability, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsServer, SledEngine,
+};
 use lazy_static::lazy_static;
-use rmp_serde;
-use slog::{self, error, info, o, Drain, Logger};
-use slog_async;
-use slog_term;
-use std::io::{Read, Write};
-use std::mem::size_of;
+use slog::{self, info, o, warn, Drain, Logger};
+use std::thread;
 use std::{
     env::current_dir,
-    net::{AddrParseError, SocketAddr, TcpListener, TcpStream},
+    net::{AddrParseError, SocketAddr, TcpListener},
 };
 use structopt::StructOpt;
 
@@ -25,7 +23,7 @@ lazy_static! {
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(about = "KvServer")]
-#[structopt(author = crate_authors!("\n"))]
+#[structopt(author = env!("CARGO_PKG_AUTHORS"))]
 #[structopt(version = crate_version!())]
 struct ServerOpt {
     #[structopt(short, long, default_value = "127.0.0.1:4000")]
@@ -33,152 +31,31 @@ struct ServerOpt {
 
     #[structopt(short, long)]
     engine: String,
-}
-const SIZE_OF_U64: usize = size_of::<u64>() as usize;
-/// Handle tcp connection from client
-fn handle_connection(
-    mut stream: TcpStream,
-    engine: &mut Box<dyn KvsEngine>,
-) -> Result<(), failure::Error> {
-    // Draw inspiration from Redis protocol
-    // let msg = b"*1\r\n$4\r\nPING\r\n";
-    // format:
-    // - * (indicate start of transmission)
-    // - big-endian u64 representing number of commands
-    // - + (indicate start of commands)
-    // - big-endian u64 representing size in bytes of following command
-    // - command
-    // - repeat for number of commands
-    let mut start = [0 as u8; 1];
-    stream.read_exact(&mut start)?;
-    match start[0] {
-        b'*' => {
-            info!(LOGGER, "initiating command sequence");
-        }
-        _ => {
-            info!(LOGGER, "incorrect initial byte");
-            return Err(failure::err_msg("incorrect initial byte"));
-        }
-    }
 
-    let mut num_commands = [0 as u8; SIZE_OF_U64];
-    stream.read_exact(&mut num_commands)?;
-    let num_commands = u64::from_be_bytes(num_commands);
-    info!(
-        LOGGER,
-        "processing {num_commands} command(s)",
-        num_commands = num_commands
-    );
+    /// when writes are synced to disk: always, group:<ms>, periodic:<ms> or none
+    #[structopt(short, long, default_value = "always")]
+    durability: Durability,
 
-    let mut commands: Vec<MPCommand> = vec![];
-    for _i in 0..num_commands {
-        let mut command_length = [0 as u8; SIZE_OF_U64];
-        stream.read_exact(&mut command_length)?;
-        let command_length = u64::from_be_bytes(command_length);
-        info!(
-            LOGGER,
-            "processing command with length {command_length}",
-            command_length = command_length
-        );
+    /// how connections are spread over threads: naive, shared or rayon
+    #[structopt(short, long, default_value = "shared")]
+    pool: String,
 
-        let mut bytes_read: u64 = 0;
-        let mut ser_command: Vec<u8> = vec![];
-        while bytes_read < command_length {
-            let mut data = [0 as u8; 50];
-            let size = stream.read(&mut data)?;
-            ser_command.extend(&data[0..size].to_vec());
-            bytes_read += size as u64;
-        }
-        let command: MPCommand = rmp_serde::decode::from_read_ref(&ser_command)?;
-        info!(LOGGER, "deserialized command");
-        dbg!(&command);
-        commands.push(command);
+    /// number of threads in the pool, defaults to the number of CPUs
+    #[structopt(short, long)]
+    threads: Option<u32>,
 
-        stream.write(b"*")?;
-        let num_values = (commands.len() as u64).to_be_bytes();
+    /// serve connections as tasks on a tokio runtime instead of a thread pool
+    #[structopt(long = "async")]
+    async_mode: bool,
+}
 
-        stream.write(&num_values)?;
-        for command in &commands {
-            // TODO: Write value to client
-            // format
-            // * , num values big_endian u64
-            // Ok: + , num_bytes (could be 0), value (string),
-            // Err: -, num_bytes, error string (could be binary format as well)
-            match command {
-                MPCommand::Get { key } => {
-                    let value = engine.get(key.clone());
-                    match value {
-                        Ok(Some(s)) => {
-                            // logic
-                            stream.write(b"+")?;
-                            let string_bytes = s.as_bytes();
-                            stream.write(&(string_bytes.len() as u64).to_be_bytes())?;
-                            stream.write(string_bytes)?;
-                        }
-                        Ok(None) => {
-                            stream.write(b"+")?;
-                            let msg = "Key not found";
-                            let msg_bytes = msg.as_bytes();
-                            stream.write(&(msg_bytes.len() as u64).to_be_bytes())?;
-                            stream.write(msg_bytes)?;
-                        }
-                        Err(_err) => {
-                            stream.write(b"-")?;
-                            let msg = "Error getting key";
-                            let msg_bytes = msg.as_bytes();
-                            stream.write(&(msg_bytes.len() as u64).to_be_bytes())?;
-                            stream.write(msg_bytes)?;
-                        }
-                    }
-                }
-                MPCommand::Set { key, value } => {
-                    let value = engine.set(key.clone(), value.clone());
-                    match value {
-                        Ok(()) => {
-                            stream.write(b"+")?;
-                            stream.write(&(0 as u64).to_be_bytes())?;
-                        }
-                        Err(_err) => {
-                            stream.write(b"-")?;
-                            let msg = "Error setting key value pair";
-                            let msg_bytes = msg.as_bytes();
-                            stream.write(&(msg_bytes.len() as u64).to_be_bytes())?;
-                            stream.write(msg_bytes)?;
-                        }
-                    }
-                }
-                MPCommand::Rm { key } => {
-                    let value = engine.remove(key.clone());
-                    match value {
-                        Ok(()) => {
-                            stream.write(b"+")?;
-                            stream.write(&(0 as u64).to_be_bytes())?;
-                        }
-                        Err(err) => match &format!("{}", err)[..] {
-                            "Key not found" => {
-                                stream.write(b"-")?;
-                                let msg = "Key not found";
-                                let msg_bytes = msg.as_bytes();
-                                stream.write(&(msg_bytes.len() as u64).to_be_bytes())?;
-                                stream.write(msg_bytes)?;
-                            }
-                            err_msg => {
-                                error!(LOGGER, "Error {err_msg}", err_msg = err_msg);
-                                stream.write(b"-")?;
-                                let msg = "Error removing key";
-                                let msg_bytes = msg.as_bytes();
-                                stream.write(&(msg_bytes.len() as u64).to_be_bytes())?;
-                                stream.write(msg_bytes)?;
-                            }
-                        },
-                    }
-                }
-            }
-        }
-    }
+/// Exit status when the data directory was created by the other engine
+const WRONG_ENGINE: i32 = 2;
+/// Exit status when another server has the data directory open
+const DIRECTORY_LOCKED: i32 = 3;
+/// Exit status when the data directory needs kvs-migrate or a newer kvs
+const UNSUPPORTED_FORMAT: i32 = 4;
 
-    Ok(())
-}
 fn main() {
     let opt = ServerOpt::from_args();
 
@@ -190,49 +67,110 @@ fn main() {
         }
     };
 
-    let mut engine: Box<dyn KvsEngine> = match &opt.engine.to_lowercase()[..] {
+    match &opt.engine.to_lowercase()[..] {
         "kvs" => {
             let cwd = current_dir().unwrap();
-            let store = KvStore::open(&cwd).unwrap();
-            Box::new(store)
+            let options = KvStoreOptions {
+                durability: opt.durability,
+                ..KvStoreOptions::default()
+            };
+            let store = open_or_exit(KvStore::open_with_options(&cwd, options));
+            if let Some(torn) = store.torn_write() {
+                warn!(
+                    LOGGER,
+                    "discarded {bytes} byte(s) of a torn write at the end of segment {segment}",
+                    bytes = torn.discarded_bytes,
+                    segment = torn.segment
+                );
+            }
+            run_with_pool(store, &opt, socket)
         }
         "sled" => {
             let cwd = current_dir().unwrap();
-            let sled = SledEngine::open(&cwd).unwrap();
-            Box::new(sled)
+            let sled = open_or_exit(SledEngine::open_with_durability(&cwd, opt.durability));
+            run_with_pool(sled, &opt, socket)
         }
         _ => {
             std::process::exit(1);
         }
-    };
+    }
+}
 
+/// Returns the opened engine, or exits with a status telling why it could not
+/// be opened
+fn open_or_exit<E: KvsEngine>(engine: kvs::Result<E>) -> E {
+    engine.unwrap_or_else(|err| {
+        // the logger drains asynchronously and would lose the message on exit
+        eprintln!("{}", err);
+        std::process::exit(match err {
+            KvsError::WrongEngine { .. } => WRONG_ENGINE,
+            KvsError::DirectoryLocked(_) => DIRECTORY_LOCKED,
+            KvsError::UnsupportedFormat(_) => UNSUPPORTED_FORMAT,
+            _ => 1,
+        })
+    })
+}
+
+fn run_with_pool<E: KvsEngine>(engine: E, opt: &ServerOpt, socket: SocketAddr) {
+    let threads = opt.threads.unwrap_or_else(|| {
+        thread::available_parallelism().map_or(1, |threads| threads.get() as u32)
+    });
+    if threads == 0 {
+        eprintln!("the server needs at least one thread");
+        std::process::exit(1);
+    }
+    if opt.async_mode {
+        log_config(opt, socket);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads as usize)
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = TcpListener::bind(socket).unwrap();
+        runtime
+            .block_on(serve_async(engine, listener, LOGGER.clone()))
+            .unwrap();
+        return;
+    }
+    match &opt.pool.to_lowercase()[..] {
+        "naive" => run(engine, NaiveThreadPool::new(threads).unwrap(), opt, socket),
+        "shared" => run(
+            engine,
+            SharedQueueThreadPool::new(threads).unwrap(),
+            opt,
+            socket,
+        ),
+        "rayon" => run(engine, RayonThreadPool::new(threads).unwrap(), opt, socket),
+        _ => {
+            std::process::exit(1);
+        }
+    }
+}
+
+fn log_config(opt: &ServerOpt, socket: SocketAddr) {
     let version = env!("CARGO_PKG_VERSION");
     info!(LOGGER, "kvs-server version {version}", version = version);
     info!(
         LOGGER,
-        "server config: {addr}:{port} {engine_name}",
+        "server config: {addr}:{port} {engine_name} durability={durability} pool={pool}",
         addr = socket.ip().to_string(),
         port = socket.port(),
-        engine_name = &opt.engine
+        engine_name = &opt.engine,
+        durability = opt.durability.to_string(),
+        pool = if opt.async_mode { "async" } else { &opt.pool }
     );
+}
 
-    let listener = TcpListener::bind(socket).unwrap();
-
-    loop {
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    info!(
-                        LOGGER,
-                        "New connection from: {peer_addr}",
-                        peer_addr = stream.peer_addr().unwrap(),
-                    );
-                    handle_connection(stream, &mut engine).unwrap();
-                }
-                Err(e) => {
-                    info!(LOGGER, "Error: {}", e);
-                }
-            }
-        }
-    }
+/// Serves clients from the given engine until the process is killed, handing
+/// every connection to the pool
+fn run<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, opt: &ServerOpt, socket: SocketAddr) {
+    log_config(opt, socket);
+    let server = KvsServer::builder()
+        .engine(engine)
+        .pool(pool)
+        .addr(socket)
+        .logger(LOGGER.clone())
+        .build()
+        .unwrap();
+    server.run().unwrap();
 }
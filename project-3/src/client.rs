@@ -0,0 +1,239 @@
+//! Client for talking to a KvsServer over TCP
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::protocol::{self, Pipeline, Reply, ScanPage, MAX_SCAN_PAGE};
+use crate::{prefix_end, KvsError, Result, WriteBatch};
+
+/// client to send requests to KvsServer
+///
+/// A client keeps a single connection open and sends every request over it.
+pub struct KvsClient {
+    connection: Connection,
+}
+
+impl KvsClient {
+    /// Connects to the server at `addr`
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            connection: Connection {
+                reader: BufReader::new(stream.try_clone()?),
+                writer: BufWriter::new(stream),
+            },
+        })
+    }
+
+    /// Gets the value of `key`, or `None` if it is not set
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Sets `key` to `value`
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Removes `key`, failing with `KvsError::KeyNotFound` if it is not set
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Gets the raw value of a binary key, or `None` if it is not set
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.request(Pipeline::new().get(key))?.into_value()
+    }
+
+    /// Gets the raw values of several keys in one round trip, with `None` for
+    /// the keys that are not set
+    pub fn mget(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let reply = self.send_one(Pipeline::new().mget(keys.iter().copied()))?;
+        let entries = protocol::decode_entries(&reply)?;
+        if entries.len() != keys.len() {
+            return Err(KvsError::Protocol(format!(
+                "asked for {} key(s) but got {} entry(s)",
+                keys.len(),
+                entries.len()
+            )));
+        }
+        entries.into_iter().map(Reply::into_value).collect()
+    }
+
+    /// Sets several keys in one round trip, either all of them or none
+    pub fn mset(&mut self, pairs: &[(&[u8], &[u8])]) -> Result<()> {
+        self.send_one(Pipeline::new().mset(pairs.iter().copied()))?;
+        Ok(())
+    }
+
+    /// Sets a binary key to a binary value
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.send_one(Pipeline::new().set(key, value))?;
+        Ok(())
+    }
+
+    /// Removes a binary key, failing with `KvsError::KeyNotFound` if it is
+    /// not set
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.send_one(Pipeline::new().remove(key))?;
+        Ok(())
+    }
+
+    /// Sets a key to a value that expires after `ttl`
+    pub fn set_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.send_one(Pipeline::new().set_with_ttl(key, value, ttl))?;
+        Ok(())
+    }
+
+    /// Makes an existing key expire after `ttl`
+    pub fn expire(&mut self, key: &[u8], ttl: Duration) -> Result<()> {
+        self.send_one(Pipeline::new().expire(key, ttl))?;
+        Ok(())
+    }
+
+    /// Returns the time left until a key expires, or `None` if it never does
+    pub fn ttl(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        protocol::decode_ttl(&self.send_one(Pipeline::new().ttl(key))?)
+    }
+
+    /// Stops an existing key from expiring
+    pub fn persist(&mut self, key: &[u8]) -> Result<()> {
+        self.send_one(Pipeline::new().persist(key))?;
+        Ok(())
+    }
+
+    /// Fetches one page of at most `limit` pairs from `start` up to `end`,
+    /// along with the cursor to fetch the next page from
+    pub fn scan_page(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        let reply = self.send_one(Pipeline::new().scan(start, end.map(<[u8]>::to_vec), limit))?;
+        ScanPage::decode(&reply)
+    }
+
+    /// Fetches the pairs from `start` up to `end` in key order, at most
+    /// `limit` of them, a page at a time
+    pub fn scan(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut pairs = vec![];
+        let mut cursor = Some(start.to_vec());
+        while let Some(start) = cursor {
+            if pairs.len() >= limit {
+                break;
+            }
+            let page = self.scan_page(&start, end, (limit - pairs.len()).min(MAX_SCAN_PAGE))?;
+            pairs.extend(page.pairs);
+            cursor = page.cursor;
+        }
+        Ok(pairs)
+    }
+
+    /// Fetches the pairs whose keys start with `prefix` in key order, at
+    /// most `limit` of them
+    pub fn scan_prefix(
+        &mut self,
+        prefix: &[u8],
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan(prefix, prefix_end(prefix).as_deref(), limit)
+    }
+
+    /// Applies every write of `batch`, or none of them if one fails
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<()> {
+        // every command of the batch gets the same reply
+        self.send_one(Pipeline::new().write_batch(batch))?;
+        Ok(())
+    }
+
+    /// Adds `delta` to the integer stored at a key and returns the result. A
+    /// missing key counts as 0.
+    pub fn incr_by(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        protocol::decode_counter(&self.send_one(Pipeline::new().incr_by(key, delta))?)
+    }
+
+    /// Adds one to the integer stored at a key and returns the result
+    pub fn incr(&mut self, key: &[u8]) -> Result<i64> {
+        self.incr_by(key, 1)
+    }
+
+    /// Subtracts one from the integer stored at a key and returns the result
+    pub fn decr(&mut self, key: &[u8]) -> Result<i64> {
+        self.incr_by(key, -1)
+    }
+
+    /// Sets a key to `new`, or removes it if `new` is `None`, but only if its
+    /// value is `expected`, with `None` standing for an absent key. Returns
+    /// whether the swap happened.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let mut pipeline = Pipeline::new();
+        pipeline.compare_and_swap(key, expected.map(<[u8]>::to_vec), new.map(<[u8]>::to_vec));
+        protocol::decode_written(&self.send_one(&pipeline)?)
+    }
+
+    /// Sets a key unless it is already set, returning whether it was set
+    pub fn set_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        protocol::decode_written(&self.send_one(Pipeline::new().set_if_absent(key, value))?)
+    }
+
+    /// Sets a key only if it is already set, returning whether it was set
+    pub fn set_if_present(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        protocol::decode_written(&self.send_one(Pipeline::new().set_if_present(key, value))?)
+    }
+
+    /// Sends every command of the pipeline in one batch and returns their
+    /// replies in order
+    pub fn pipeline(&mut self, pipeline: &Pipeline) -> Result<Vec<Reply>> {
+        pipeline.send(&mut self.connection)
+    }
+
+    fn send_one(&mut self, pipeline: &Pipeline) -> Result<Vec<u8>> {
+        self.request(pipeline)?.into_result()
+    }
+
+    /// Sends a pipeline of a single command and returns its reply
+    fn request(&mut self, pipeline: &Pipeline) -> Result<Reply> {
+        Ok(self
+            .pipeline(pipeline)?
+            .pop()
+            .expect("pipeline checks the number of replies"))
+    }
+}
+
+/// Buffered reads and writes over one TCP stream
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
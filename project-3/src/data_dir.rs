@@ -0,0 +1,70 @@
+//! Claims a data directory for one store and one engine at a time
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::filesystem::{Filesystem, LockGuard};
+use crate::kv_store::{segment_ids, LEGACY_LOG_FILE};
+use crate::{KvsError, Result};
+
+/// held with an exclusive lock for as long as a store has the directory open
+const LOCK_FILE: &str = "kvs.lock";
+/// names the engine that created the directory
+const ENGINE_FILE: &str = "kvs.engine";
+
+/// Exclusive claim on a data directory, released when dropped
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _guard: LockGuard,
+}
+
+/// Locks `dir` for the calling store and checks that it was created by
+/// `engine`, marking it as such on first open. Fails with
+/// `KvsError::DirectoryLocked` if another store has the directory open, and
+/// with `KvsError::WrongEngine` if the directory belongs to another engine.
+pub(crate) fn claim(fs: &dyn Filesystem, dir: &Path, engine: &str) -> Result<DirLock> {
+    let guard = match fs.lock(&dir.join(LOCK_FILE)) {
+        Ok(guard) => guard,
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+            return Err(KvsError::DirectoryLocked(dir.to_owned()))
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let found = match fs.read(&dir.join(ENGINE_FILE)) {
+        Ok(found) => Some(String::from_utf8_lossy(&found).trim().to_owned()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => existing_engine(fs, dir)?,
+        Err(err) => return Err(err.into()),
+    };
+    match found {
+        Some(found) if found != engine => {
+            return Err(KvsError::WrongEngine {
+                requested: engine.to_owned(),
+                found,
+            })
+        }
+        Some(_) => (),
+        None => {
+            let mut marker = fs.create(&dir.join(ENGINE_FILE))?;
+            writeln!(marker, "{}", engine)?;
+            marker.sync_all()?;
+        }
+    }
+    Ok(DirLock { _guard: guard })
+}
+
+/// Works out which engine wrote the data in a directory from before engine
+/// markers, or `None` if it holds no data
+fn existing_engine(fs: &dyn Filesystem, dir: &Path) -> Result<Option<String>> {
+    if fs.exists(&dir.join("conf")) || fs.exists(&dir.join("db")) {
+        return Ok(Some("sled".to_owned()));
+    }
+    if fs.exists(&dir.join(LEGACY_LOG_FILE)) {
+        return Ok(Some("kvs".to_owned()));
+    }
+    // other `.log` files, like the output of a server, are no sign of kvs
+    if !segment_ids(fs, dir)?.is_empty() {
+        return Ok(Some("kvs".to_owned()));
+    }
+    Ok(None)
+}
@@ -0,0 +1,256 @@
+//! Policies deciding when writes are forced to stable storage
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{KvsError, Result};
+
+/// When an engine forces its writes to stable storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// sync before acknowledging every write
+    Always,
+    /// writes arriving within the window share a single sync, and each one is
+    /// acknowledged once a sync covering it has finished
+    GroupCommit(Duration),
+    /// writes are acknowledged right away and synced in the background at
+    /// the given interval
+    Periodic(Duration),
+    /// leave flushing to the operating system
+    Never,
+}
+
+impl Default for Durability {
+    /// Syncs every write, so an acknowledged write is never lost
+    fn default() -> Self {
+        Durability::Always
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::GroupCommit(window) => write!(f, "group:{}", window.as_millis()),
+            Durability::Periodic(interval) => write!(f, "periodic:{}", interval.as_millis()),
+            Durability::Never => write!(f, "none"),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    /// Parses `always`, `group:<ms>`, `periodic:<ms>` or `none`
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, millis) = match s.split_once(':') {
+            Some((name, millis)) => (name, Some(millis)),
+            None => (s, None),
+        };
+        let duration = || -> Result<Duration> {
+            let millis = millis.ok_or_else(|| {
+                KvsError::InvalidConfig(format!(
+                    "durability policy {} needs an interval in ms",
+                    name
+                ))
+            })?;
+            let millis = millis
+                .parse()
+                .map_err(|_| KvsError::InvalidConfig(format!("invalid interval {}", millis)))?;
+            Ok(Duration::from_millis(millis))
+        };
+        match (&name.to_lowercase()[..], millis) {
+            ("always", None) => Ok(Durability::Always),
+            ("none", None) => Ok(Durability::Never),
+            ("group", _) => Ok(Durability::GroupCommit(duration()?)),
+            ("periodic", _) => Ok(Durability::Periodic(duration()?)),
+            _ => Err(KvsError::InvalidConfig(format!(
+                "unknown durability policy {}",
+                s
+            ))),
+        }
+    }
+}
+
+type SyncFn = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+#[derive(Default)]
+struct SyncState {
+    /// number of writes handed to the syncer so far
+    written: u64,
+    /// every write up to this one has been covered by a sync
+    synced: u64,
+    /// set by the first sync that fails. A sync that works later does not
+    /// prove the earlier writes reached the disk, so from then on no write
+    /// is acknowledged.
+    failed: Option<String>,
+    shutdown: bool,
+}
+
+impl SyncState {
+    /// Fails once a sync has failed
+    fn check(&self) -> Result<()> {
+        match &self.failed {
+            None => Ok(()),
+            Some(err) => Err(KvsError::LogUnwritable(format!("sync failed: {}", err))),
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<SyncState>,
+    cond: Condvar,
+    sync: SyncFn,
+}
+
+impl Shared {
+    /// Runs a sync covering every write made so far
+    fn sync_written(&self) {
+        let to = self.state.lock().unwrap().written;
+        let result = self.sync();
+        let mut state = self.state.lock().unwrap();
+        if result.is_ok() {
+            state.synced = state.synced.max(to);
+        }
+        self.cond.notify_all();
+    }
+
+    /// Runs a sync, remembering it if it fails
+    fn sync(&self) -> Result<()> {
+        let result = (self.sync)();
+        if let Err(err) = &result {
+            let mut state = self.state.lock().unwrap();
+            state.failed.get_or_insert_with(|| err.to_string());
+        }
+        result
+    }
+}
+
+/// Applies a `Durability` policy on behalf of an engine. The engine calls
+/// `after_write` once a write has been handed to the OS, and the syncer
+/// decides whether to sync right away, wait for a group sync or let a
+/// background thread take care of it.
+pub(crate) struct Syncer {
+    policy: Durability,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    /// Creates a syncer that runs `sync` to make writes durable
+    pub(crate) fn new(
+        policy: Durability,
+        sync: impl Fn() -> Result<()> + Send + Sync + 'static,
+    ) -> Syncer {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(SyncState::default()),
+            cond: Condvar::new(),
+            sync: Box::new(sync),
+        });
+        let thread = match policy {
+            Durability::GroupCommit(window) => {
+                let shared = Arc::clone(&shared);
+                Some(thread::spawn(move || run_group_commit(&shared, window)))
+            }
+            Durability::Periodic(interval) => {
+                let shared = Arc::clone(&shared);
+                Some(thread::spawn(move || run_periodic(&shared, interval)))
+            }
+            Durability::Always | Durability::Never => None,
+        };
+        Syncer {
+            policy,
+            shared,
+            thread,
+        }
+    }
+
+    /// Returns the policy this syncer applies
+    pub(crate) fn policy(&self) -> Durability {
+        self.policy
+    }
+
+    /// Makes a write that has just been handed to the OS as durable as the
+    /// policy requires. Returns once the write may be acknowledged. Once a
+    /// sync has failed every write fails with `KvsError::LogUnwritable`
+    /// until the engine is reopened.
+    pub(crate) fn after_write(&self) -> Result<()> {
+        match self.policy {
+            Durability::Always => {
+                self.shared.state.lock().unwrap().check()?;
+                self.shared.sync()
+            }
+            Durability::Never => Ok(()),
+            Durability::Periodic(_) => {
+                let mut state = self.shared.state.lock().unwrap();
+                state.check()?;
+                state.written += 1;
+                Ok(())
+            }
+            Durability::GroupCommit(_) => {
+                let mut state = self.shared.state.lock().unwrap();
+                state.check()?;
+                state.written += 1;
+                let seq = state.written;
+                self.shared.cond.notify_all();
+                while state.synced < seq && state.failed.is_none() {
+                    state = self.shared.cond.wait(state).unwrap();
+                }
+                if state.synced >= seq {
+                    return Ok(());
+                }
+                state.check()
+            }
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.cond.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run_group_commit(shared: &Shared, window: Duration) {
+    loop {
+        {
+            let mut state = shared.state.lock().unwrap();
+            while state.written == state.synced && !state.shutdown && state.failed.is_none() {
+                state = shared.cond.wait(state).unwrap();
+            }
+            // after a failed sync there is nothing left to acknowledge
+            if state.written == state.synced || state.failed.is_some() {
+                return;
+            }
+        }
+        // give other writers the window to join this sync
+        thread::sleep(window);
+        shared.sync_written();
+    }
+}
+
+fn run_periodic(shared: &Shared, interval: Duration) {
+    loop {
+        let pending = {
+            let state = shared.state.lock().unwrap();
+            let (state, _) = shared
+                .cond
+                .wait_timeout_while(state, interval, |state| !state.shutdown)
+                .unwrap();
+            if state.failed.is_some() || (state.shutdown && state.written == state.synced) {
+                return;
+            }
+            state.written > state.synced
+        };
+        if pending {
+            shared.sync_written();
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Error type shared by the storage engines, the client and the server
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::AddrParseError;
+use std::path::PathBuf;
+use std::string::FromUtf8Error;
+
+use sled::transaction::TransactionError;
+
+/// Everything that can go wrong in kvs
+#[derive(Debug)]
+pub enum KvsError {
+    /// the key to remove is not in the store
+    KeyNotFound,
+    /// reading or writing a file or socket failed
+    Io(io::Error),
+    /// a command could not be encoded or decoded
+    Serialization(String),
+    /// a record failed its checksum or could not be decoded
+    Corruption {
+        /// id of the segment holding the record
+        segment: u64,
+        /// byte offset of the record within the segment
+        offset: u64,
+    },
+    /// the log held a different command than the index pointed at
+    UnexpectedCommand,
+    /// a value or message was not valid UTF-8
+    Utf8(FromUtf8Error),
+    /// the other side of a connection broke the wire protocol
+    Protocol(String),
+    /// the server failed to carry out a command, with its message
+    Server(String),
+    /// sled reported an error
+    Sled(sled::Error),
+    /// an option or address could not be used
+    InvalidConfig(String),
+    /// a background sync or compaction failed
+    Background(String),
+    /// the stored value does not suit the operation, such as a counter that
+    /// does not hold an integer
+    InvalidValue(String),
+    /// another store has the data directory open
+    DirectoryLocked(PathBuf),
+    /// the data directory was created by a different engine
+    WrongEngine {
+        /// engine the directory was opened with
+        requested: String,
+        /// engine that created the directory
+        found: String,
+    },
+    /// a log file was written in a format this kvs cannot read, either by an
+    /// older kvs and in need of kvs-migrate or by a newer one
+    UnsupportedFormat(String),
+    /// an earlier write, sync or compaction failed part way, so the store
+    /// refuses writes until it is reopened
+    LogUnwritable(String),
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::Io(err) => write!(f, "I/O error: {}", err),
+            KvsError::Serialization(msg) => write!(f, "serialization error: {}", msg),
+            KvsError::Corruption { segment, offset } => write!(
+                f,
+                "corrupt record in segment {} at offset {}",
+                segment, offset
+            ),
+            KvsError::UnexpectedCommand => write!(f, "unexpected command in log"),
+            KvsError::Utf8(err) => write!(f, "invalid UTF-8: {}", err),
+            KvsError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            KvsError::Server(msg) => write!(f, "{}", msg),
+            KvsError::Sled(err) => write!(f, "sled error: {}", err),
+            KvsError::InvalidConfig(msg) => write!(f, "invalid configuration: {}", msg),
+            KvsError::Background(msg) => write!(f, "{}", msg),
+            KvsError::InvalidValue(msg) => write!(f, "invalid value: {}", msg),
+            KvsError::DirectoryLocked(dir) => {
+                write!(f, "{} is already open in another store", dir.display())
+            }
+            KvsError::WrongEngine { requested, found } => write!(
+                f,
+                "data directory was created by the {} engine and cannot be opened with {}",
+                found, requested
+            ),
+            KvsError::UnsupportedFormat(msg) => write!(f, "unsupported log format: {}", msg),
+            KvsError::LogUnwritable(msg) => write!(
+                f,
+                "the log cannot be written until the store is reopened, an earlier write failed: {}",
+                msg
+            ),
+        }
+    }
+}
+
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::Io(err) => Some(err),
+            KvsError::Utf8(err) => Some(err),
+            KvsError::Sled(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvsError {
+    fn from(err: io::Error) -> Self {
+        KvsError::Io(err)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for KvsError {
+    fn from(err: rmp_serde::encode::Error) -> Self {
+        KvsError::Serialization(err.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for KvsError {
+    fn from(err: rmp_serde::decode::Error) -> Self {
+        KvsError::Serialization(err.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> Self {
+        KvsError::Utf8(err)
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> Self {
+        KvsError::Sled(err)
+    }
+}
+
+impl From<TransactionError<KvsError>> for KvsError {
+    fn from(err: TransactionError<KvsError>) -> Self {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => KvsError::Sled(err),
+        }
+    }
+}
+
+impl From<AddrParseError> for KvsError {
+    fn from(err: AddrParseError) -> Self {
+        KvsError::InvalidConfig(err.to_string())
+    }
+}
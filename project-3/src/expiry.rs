@@ -0,0 +1,71 @@
+//! Key expiry deadlines, kept as milliseconds since the Unix epoch
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the Unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// The deadline of a key that expires `ttl` from now
+pub(crate) fn deadline(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+/// Returns true once `expires_at` has passed
+pub(crate) fn is_expired(expires_at: Option<u64>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now())
+}
+
+/// Time left until `expires_at`
+pub(crate) fn remaining(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now()))
+}
+
+/// Runs a sweep of expired keys on a background thread at a fixed interval,
+/// until it is dropped
+pub(crate) struct Sweeper {
+    /// set to stop the thread
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    /// Starts running `sweep` every `interval`
+    pub(crate) fn new(interval: Duration, sweep: impl Fn() + Send + 'static) -> Sweeper {
+        let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || loop {
+                let (lock, cond) = &*shutdown;
+                let (stopped, _) = cond
+                    .wait_timeout_while(lock.lock().unwrap(), interval, |stopped| !*stopped)
+                    .unwrap();
+                if *stopped {
+                    return;
+                }
+                drop(stopped);
+                sweep();
+            })
+        };
+        Sweeper {
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        let (lock, cond) = &*self.shutdown;
+        *lock.lock().unwrap() = true;
+        cond.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
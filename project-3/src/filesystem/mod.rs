@@ -0,0 +1,156 @@
+//! File access for KvStore, either through the real filesystem or, with the
+//! `sim` feature, through a simulated one that injects faults and crashes
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+#[cfg(any(test, feature = "sim"))]
+mod sim;
+
+#[cfg(any(test, feature = "sim"))]
+pub use self::sim::{Fault, SimFs};
+
+/// Held for as long as a lock taken with `Filesystem::lock` should last
+pub type LockGuard = Box<dyn fmt::Debug + Send + Sync>;
+
+/// The file operations a KvStore performs
+pub trait Filesystem: fmt::Debug + Send + Sync {
+    /// Opens an existing file for reading
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FsFile>>;
+
+    /// Creates a file for writing, truncating it if it exists
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FsFile>>;
+
+    /// Opens a file for appending, creating it if it does not exist
+    fn append(&self, path: &Path) -> io::Result<Box<dyn FsFile>>;
+
+    /// Reads a whole file
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = vec![];
+        self.open(path)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Returns true if a file or directory exists at `path`
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Renames a file, replacing `to` if it exists
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes a file
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates a directory along with any missing parents
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Lists the entries of a directory
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Makes the creations, renames and removals of entries in a directory
+    /// durable
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Takes an exclusive lock on the file at `path`, creating it if needed.
+    /// Fails with `io::ErrorKind::WouldBlock` if the lock is already held.
+    fn lock(&self, path: &Path) -> io::Result<LockGuard>;
+}
+
+/// An open file of a `Filesystem`
+pub trait FsFile: Read + Write + Seek + fmt::Debug + Send + Sync {
+    /// Returns the current size of the file
+    fn size(&self) -> io::Result<u64>;
+
+    /// Truncates or extends the file to `len` bytes
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Makes the contents of the file durable
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Makes the contents and metadata of the file durable
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// Opens a second handle on the same file
+    fn try_clone(&self) -> io::Result<Box<dyn FsFile>>;
+}
+
+/// The filesystem of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl Filesystem for RealFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FsFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FsFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn FsFile>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<LockGuard> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Box::new(file)),
+            Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
+
+impl FsFile for File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn FsFile>> {
+        Ok(Box::new(File::try_clone(self)?))
+    }
+}
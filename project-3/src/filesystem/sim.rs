@@ -0,0 +1,493 @@
+//! An in-memory filesystem for tests, which injects faults and simulates
+//! crashes
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{Filesystem, FsFile, LockGuard};
+
+/// A failure `SimFs` can inject
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// a write stores only part of its buffer and reports so
+    ShortWrite,
+    /// an operation fails with EIO; a write may store part of its buffer
+    /// before failing
+    Io,
+    /// creating a file or writing fails with ENOSPC; a write may store part
+    /// of its buffer before failing
+    NoSpace,
+    /// syncing a file fails with EIO and leaves its data unsynced
+    Sync,
+}
+
+/// An in-memory filesystem that injects faults at random and can simulate a
+/// crash of the machine
+///
+/// Written data only survives a crash once its file has been synced, and
+/// created, renamed or removed files only once their directory has been
+/// synced. A crash keeps a random part of the unsynced data appended to each
+/// file, the way a torn write would. A sync that fails leaves the data
+/// unsynced for a later sync to pick up.
+///
+/// Clones share the same files, so one clone can be handed to a store while
+/// the test keeps another to inject faults and crashes.
+#[derive(Debug, Clone)]
+pub struct SimFs {
+    state: Arc<Mutex<SimState>>,
+}
+
+#[derive(Debug)]
+struct SimState {
+    rng: StdRng,
+    /// bumped by every crash, handles and locks from before it stop working
+    epoch: u64,
+    /// set by `halt` until the crash that follows it
+    halted: bool,
+    next_inode: u64,
+    inodes: HashMap<u64, Inode>,
+    /// the files as they are seen until the next crash
+    names: BTreeMap<PathBuf, u64>,
+    /// the files as they come back after a crash
+    durable_names: BTreeMap<PathBuf, u64>,
+    dirs: BTreeSet<PathBuf>,
+    locks: BTreeSet<PathBuf>,
+    faults: Vec<(Fault, f64)>,
+    injected: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inode {
+    data: Vec<u8>,
+    /// the contents as of the last sync
+    synced: Vec<u8>,
+}
+
+/// The kinds of operation other than writes that faults are injected into
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    /// creating or truncating a file, which can run out of space
+    Create,
+    /// syncing a directory, renaming, removing or resizing
+    Modify,
+    /// syncing a file
+    SyncFile,
+}
+
+impl SimFs {
+    /// Creates an empty filesystem whose faults and crashes are drawn from
+    /// `seed`
+    pub fn new(seed: u64) -> Self {
+        SimFs {
+            state: Arc::new(Mutex::new(SimState {
+                rng: StdRng::seed_from_u64(seed),
+                epoch: 0,
+                halted: false,
+                next_inode: 0,
+                inodes: HashMap::new(),
+                names: BTreeMap::new(),
+                durable_names: BTreeMap::new(),
+                dirs: BTreeSet::new(),
+                locks: BTreeSet::new(),
+                faults: vec![],
+                injected: 0,
+            })),
+        }
+    }
+
+    /// Makes every operation that `fault` applies to fail with it at the
+    /// given probability
+    pub fn inject(&self, fault: Fault, probability: f64) {
+        self.lock_state().faults.push((fault, probability));
+    }
+
+    /// Stops injecting faults
+    pub fn clear_faults(&self) {
+        self.lock_state().faults.clear();
+    }
+
+    /// Returns the number of faults injected so far
+    pub fn faults_injected(&self) -> u64 {
+        self.lock_state().injected
+    }
+
+    /// Stops the machine: every operation fails from now on, so that the
+    /// store using the filesystem can be dropped without touching the files
+    /// before `crash` restarts it
+    pub fn halt(&self) {
+        self.lock_state().halted = true;
+    }
+
+    /// Simulates a crash: every file goes back to what was last synced,
+    /// plus a random part of what was appended since, and open handles and
+    /// locks stop working
+    pub fn crash(&self) {
+        let mut state = self.lock_state();
+        let state = &mut *state;
+        state.epoch += 1;
+        state.halted = false;
+        state.locks.clear();
+        state.names = state.durable_names.clone();
+        let live: BTreeSet<u64> = state.names.values().cloned().collect();
+        state.inodes.retain(|id, _| live.contains(id));
+        for inode in state.inodes.values_mut() {
+            if inode.data.starts_with(&inode.synced) {
+                let kept = state.rng.gen_range(inode.synced.len()..=inode.data.len());
+                inode.data.truncate(kept);
+            } else {
+                // rewritten in place since the sync
+                inode.data = inode.synced.clone();
+            }
+            inode.synced = inode.data.clone();
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Locks the state for an operation, failing while the machine is halted
+    fn live_state(&self) -> io::Result<MutexGuard<'_, SimState>> {
+        let state = self.lock_state();
+        if state.halted {
+            return Err(io::Error::other("the machine is halted"));
+        }
+        Ok(state)
+    }
+
+    /// Locks the state for a handle opened in `epoch`, failing if a crash
+    /// has happened since
+    fn handle_state(&self, epoch: u64) -> io::Result<MutexGuard<'_, SimState>> {
+        let state = self.live_state()?;
+        if state.epoch != epoch {
+            return Err(io::Error::other("file handle from before a crash"));
+        }
+        Ok(state)
+    }
+
+    fn open_file(
+        &self,
+        path: &Path,
+        create: bool,
+        truncate: bool,
+        append: bool,
+    ) -> io::Result<Box<dyn FsFile>> {
+        let mut state = self.live_state()?;
+        let inode = match state.names.get(path) {
+            Some(&inode) => inode,
+            None if create => {
+                state.check_parent(path)?;
+                state.roll(Op::Create)?;
+                let inode = state.next_inode;
+                state.next_inode += 1;
+                state.inodes.insert(inode, Inode::default());
+                state.names.insert(path.to_owned(), inode);
+                inode
+            }
+            None => return Err(not_found(path)),
+        };
+        if truncate {
+            state.roll(Op::Create)?;
+            state.inode_mut(inode).data.clear();
+        }
+        Ok(Box::new(SimFile {
+            fs: self.clone(),
+            inode,
+            epoch: state.epoch,
+            pos: 0,
+            writable: create,
+            append,
+        }))
+    }
+}
+
+impl SimState {
+    /// Decides whether an operation of kind `op` fails with an injected fault
+    fn roll(&mut self, op: Op) -> io::Result<()> {
+        for index in 0..self.faults.len() {
+            let (fault, probability) = self.faults[index];
+            let applies = match fault {
+                Fault::ShortWrite => false,
+                Fault::Io => true,
+                Fault::NoSpace => op == Op::Create,
+                Fault::Sync => op == Op::SyncFile,
+            };
+            if applies && self.rng.gen_bool(probability) {
+                self.injected += 1;
+                return Err(fault_error(fault));
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks the fault a write runs into, if any
+    fn roll_write(&mut self) -> Option<Fault> {
+        for index in 0..self.faults.len() {
+            let (fault, probability) = self.faults[index];
+            if fault != Fault::Sync && self.rng.gen_bool(probability) {
+                self.injected += 1;
+                return Some(fault);
+            }
+        }
+        None
+    }
+
+    fn inode_mut(&mut self, inode: u64) -> &mut Inode {
+        self.inodes.entry(inode).or_default()
+    }
+
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !self.dirs.contains(parent) => Err(not_found(parent)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn fault_error(fault: Fault) -> io::Error {
+    match fault {
+        Fault::NoSpace => io::Error::new(io::ErrorKind::StorageFull, "no space left on device"),
+        Fault::ShortWrite | Fault::Io | Fault::Sync => io::Error::other("input/output error"),
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+impl Filesystem for SimFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FsFile>> {
+        self.open_file(path, false, false, false)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FsFile>> {
+        self.open_file(path, true, true, false)
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn FsFile>> {
+        self.open_file(path, true, false, true)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.lock_state();
+        state.names.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.live_state()?;
+        let inode = *state.names.get(from).ok_or_else(|| not_found(from))?;
+        state.check_parent(to)?;
+        state.roll(Op::Modify)?;
+        state.names.remove(from);
+        state.names.insert(to.to_owned(), inode);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.live_state()?;
+        if !state.names.contains_key(path) {
+            return Err(not_found(path));
+        }
+        state.roll(Op::Modify)?;
+        state.names.remove(path);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        // directories are durable as soon as they exist
+        let mut state = self.live_state()?;
+        state.dirs.extend(path.ancestors().map(Path::to_owned));
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.live_state()?;
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        let in_dir = |entry: &&PathBuf| entry.parent() == Some(path);
+        Ok(state
+            .names
+            .keys()
+            .chain(&state.dirs)
+            .filter(in_dir)
+            .cloned()
+            .collect())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.live_state()?;
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        state.roll(Op::Modify)?;
+        let state = &mut *state;
+        state
+            .durable_names
+            .retain(|name, _| name.parent() != Some(path));
+        for (name, inode) in &state.names {
+            if name.parent() == Some(path) {
+                state.durable_names.insert(name.clone(), *inode);
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<LockGuard> {
+        let mut state = self.live_state()?;
+        state.check_parent(path)?;
+        if !state.locks.insert(path.to_owned()) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(Box::new(SimLock {
+            fs: self.clone(),
+            path: path.to_owned(),
+            epoch: state.epoch,
+        }))
+    }
+}
+
+/// A lock taken on a `SimFs`, released when dropped
+#[derive(Debug)]
+struct SimLock {
+    fs: SimFs,
+    path: PathBuf,
+    epoch: u64,
+}
+
+impl Drop for SimLock {
+    fn drop(&mut self) {
+        let mut state = self.fs.lock_state();
+        // a crash already released it, and someone else may hold it now
+        if state.epoch == self.epoch {
+            state.locks.remove(&self.path);
+        }
+    }
+}
+
+/// An open file of a `SimFs`
+#[derive(Debug)]
+struct SimFile {
+    fs: SimFs,
+    inode: u64,
+    epoch: u64,
+    pos: u64,
+    writable: bool,
+    append: bool,
+}
+
+impl Read for SimFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.fs.handle_state(self.epoch)?;
+        let data = &state.inode_mut(self.inode).data;
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for SimFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not open for writing",
+            ));
+        }
+        let mut state = self.fs.handle_state(self.epoch)?;
+        let fault = state.roll_write();
+        let len = match fault {
+            None => buf.len(),
+            Some(_) if buf.is_empty() => 0,
+            Some(Fault::ShortWrite) => state.rng.gen_range(1..=buf.len()),
+            Some(Fault::Io | Fault::NoSpace) => state.rng.gen_range(0..buf.len()),
+            Some(Fault::Sync) => unreachable!("writes never fail a sync"),
+        };
+        let inode = state.inode_mut(self.inode);
+        if self.append {
+            self.pos = inode.data.len() as u64;
+        }
+        let start = self.pos as usize;
+        if inode.data.len() < start + len {
+            inode.data.resize(start + len, 0);
+        }
+        inode.data[start..start + len].copy_from_slice(&buf[..len]);
+        self.pos += len as u64;
+        match fault {
+            Some(fault @ (Fault::Io | Fault::NoSpace)) => Err(fault_error(fault)),
+            _ => Ok(len),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.fs.handle_state(self.epoch).map(drop)
+    }
+}
+
+impl Seek for SimFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut state = self.fs.handle_state(self.epoch)?;
+        let len = state.inode_mut(self.inode).data.len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl FsFile for SimFile {
+    fn size(&self) -> io::Result<u64> {
+        let mut state = self.fs.handle_state(self.epoch)?;
+        Ok(state.inode_mut(self.inode).data.len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut state = self.fs.handle_state(self.epoch)?;
+        state.roll(Op::Modify)?;
+        state.inode_mut(self.inode).data.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        let mut state = self.fs.handle_state(self.epoch)?;
+        state.roll(Op::SyncFile)?;
+        let inode = state.inode_mut(self.inode);
+        inode.synced = inode.data.clone();
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn FsFile>> {
+        drop(self.fs.handle_state(self.epoch)?);
+        Ok(Box::new(SimFile {
+            fs: self.fs.clone(),
+            inode: self.inode,
+            epoch: self.epoch,
+            pos: self.pos,
+            writable: self.writable,
+            append: self.append,
+        }))
+    }
+}
@@ -0,0 +1,1646 @@
+//! Log-structured storage engine that keeps its data in numbered segment files
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use rmp_serde::Serializer;
+use serde::{Deserialize, Serialize};
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::env;
+use std::io::{self, prelude::*, BufReader, BufWriter, SeekFrom};
+use std::iter;
+use std::mem::{self, size_of};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::data_dir::{self, DirLock};
+use crate::durability::Syncer;
+use crate::expiry;
+use crate::filesystem::{Filesystem, FsFile, RealFs};
+use crate::{
+    add_to_counter, Durability, KvPairs, KvsEngine, KvsError, MPCommand, Result, WriteBatch,
+};
+
+const SIZE_OF_U64: u64 = size_of::<u64>() as u64;
+const SIZE_OF_U32: u64 = size_of::<u32>() as u64;
+/// every record starts with its payload length followed by a CRC32 of the payload
+const RECORD_HEADER_LEN: u64 = SIZE_OF_U64 + SIZE_OF_U32;
+
+/// every segment starts with the magic, the format version and the feature
+/// flags it was written with
+pub(crate) const SEGMENT_MAGIC: [u8; 4] = *b"KVSL";
+/// Version of the segment format written by this kvs
+pub const FORMAT_VERSION: u32 = 1;
+/// feature flags this kvs knows how to read, none so far
+const KNOWN_FLAGS: u32 = 0;
+pub(crate) const SEGMENT_HEADER_LEN: u64 = 4 + SIZE_OF_U32 + SIZE_OF_U32;
+
+pub(crate) const SEGMENT_EXTENSION: &str = "log";
+/// the single log file written by kvs before segments
+pub(crate) const LEGACY_LOG_FILE: &str = "my-file";
+/// snapshot of the index that lets open skip replaying the segments it covers
+pub(crate) const HINT_FILE: &str = "index.hint";
+const HINT_TMP_FILE: &str = "index.hint.tmp";
+/// extension of a compaction output that has not been renamed into place yet
+const COMPACT_EXTENSION: &str = "compact";
+
+/// Size at which the active segment is sealed and a new one is started
+pub const DEFAULT_MAX_SEGMENT_BYTES: u64 = 1024 * 1024;
+
+/// Tunables for a KvStore
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// size in bytes after which the active segment is sealed
+    pub max_segment_bytes: u64,
+    /// when appended records are synced to disk
+    pub durability: Durability,
+    /// where the files of the store live, the real filesystem unless a test
+    /// swaps in a simulated one
+    pub filesystem: Arc<dyn Filesystem>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            max_segment_bytes: DEFAULT_MAX_SEGMENT_BYTES,
+            durability: Durability::default(),
+            filesystem: Arc::new(RealFs),
+        }
+    }
+}
+
+/// A partially written record found at the end of the log on open. Such a
+/// record is left behind when the process dies in the middle of a write; it
+/// is truncated away since the write was never acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TornWrite {
+    /// id of the segment that held the record
+    pub segment: u64,
+    /// offset the segment was truncated to
+    pub offset: u64,
+    /// number of bytes that were discarded
+    pub discarded_bytes: u64,
+}
+
+/// Location of a record in the segmented log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LogPointer {
+    segment: u64,
+    offset: u64,
+    /// length of the whole record, including its length prefix
+    len: u64,
+    /// when the key set by the record expires
+    expires_at: Option<u64>,
+}
+
+/// Live keys mapped to their latest `Set` record
+type Index = BTreeMap<Vec<u8>, LogPointer>;
+
+/// Snapshot of the index written at the end of a compaction, framed like a
+/// record so that a damaged hint is caught by its checksum
+#[derive(Serialize, Deserialize)]
+struct Hint {
+    /// first segment the snapshot does not cover, which is replayed on open
+    /// along with every later segment
+    tail: u64,
+    /// ids and lengths of the segments the snapshot covers
+    segments: Vec<(u64, u64)>,
+    entries: Vec<HintEntry>,
+}
+
+/// Where the value of one key lives, as of the hint
+#[derive(Serialize, Deserialize)]
+struct HintEntry {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+    segment: u64,
+    offset: u64,
+    len: u64,
+    expires_at: Option<u64>,
+}
+
+/// Bookkeeping for a single segment file
+#[derive(Debug, Clone, Copy)]
+struct SegmentStats {
+    len: u64,
+    /// bytes taken up by records that have been overwritten or removed
+    stale: u64,
+}
+
+impl SegmentStats {
+    /// a segment holding only its header, which counts as garbage
+    fn new() -> Self {
+        SegmentStats {
+            len: SEGMENT_HEADER_LEN,
+            stale: SEGMENT_HEADER_LEN,
+        }
+    }
+
+    /// a segment is worth rewriting once at least half of it is garbage
+    fn is_compactable(&self) -> bool {
+        self.stale * 2 >= self.len
+    }
+}
+
+/// Log state shared between a KvStore and its background compaction
+struct Shared {
+    fs: Arc<dyn Filesystem>,
+    dir: PathBuf,
+    index: RwLock<Index>,
+    segments: Mutex<BTreeMap<u64, SegmentStats>>,
+    /// bumped every time compaction deletes segments, so that cached read
+    /// handles on them can be dropped
+    generation: AtomicU64,
+    /// set while a compaction is running, whether its handle is with the
+    /// writer or with a `compact` call waiting on it
+    compacting: AtomicBool,
+    /// lets one `compact` call at a time wait on a running compaction
+    compact_calls: Mutex<()>,
+    /// keeps other stores out of the directory
+    _lock: DirLock,
+}
+
+impl Shared {
+    /// Returns where the value of `key` lives, unless it has expired
+    fn lookup(&self, key: &[u8]) -> Option<LogPointer> {
+        self.index
+            .read()
+            .unwrap()
+            .get(key)
+            .filter(|pointer| !expiry::is_expired(pointer.expires_at))
+            .cloned()
+    }
+
+    /// Drops expired keys from the index, turning their values into garbage
+    /// that compaction can reclaim
+    fn sweep_expired(&self) {
+        let mut expired = vec![];
+        self.index.write().unwrap().retain(|_, pointer| {
+            let live = !expiry::is_expired(pointer.expires_at);
+            if !live {
+                expired.push(*pointer);
+            }
+            live
+        });
+        for pointer in expired {
+            self.mark_stale(pointer);
+        }
+    }
+
+    fn mark_stale(&self, pointer: LogPointer) {
+        if let Some(stats) = self.segments.lock().unwrap().get_mut(&pointer.segment) {
+            stats.stale += pointer.len;
+        }
+    }
+}
+
+/// Main struct implementing key-value store functionality
+///
+/// Records are appended to the active segment until it reaches
+/// `max_segment_bytes`, at which point it is sealed and a new segment with the
+/// next id is started. The index keeps every live key in order, mapped to the
+/// segment and offset of its latest `Set` record. Sealed segments that are
+/// mostly garbage are compacted on a background thread while reads and writes
+/// carry on.
+///
+/// A KvStore is cheap to clone. Clones share the log and serialize their
+/// writes through one writer, but each clone reads through its own file
+/// handles, so handing a clone to every thread lets reads run concurrently.
+#[derive(Clone)]
+pub struct KvStore {
+    shared: Arc<Shared>,
+    reader: KvStoreReader,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    syncer: Arc<Syncer>,
+    torn_write: Option<TornWrite>,
+}
+
+impl KvsEngine for KvStore {
+    ///
+    /// Gets a value from the key-value store
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ///
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    ///
+    /// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    ///
+    /// store.set_bytes(vec![0xff, 0], vec![0xc3, 0x28]).unwrap();
+    /// assert_eq!(store.get_bytes(&[0xff, 0]).unwrap(), Some(vec![0xc3, 0x28]));
+    /// ```
+    fn get_bytes(&self, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.reader.get(k)
+    }
+
+    /// Used to set key in store
+    fn set_bytes(&self, k: Vec<u8>, v: Vec<u8>) -> Result<()> {
+        self.writer.lock().unwrap().set(k, v, None)?;
+        // wait for durability outside the writer lock so that concurrent
+        // writers can share a group commit
+        self.syncer.after_write()
+    }
+
+    /// Used to remove key from store
+    fn remove_bytes(&self, k: &[u8]) -> Result<()> {
+        self.writer.lock().unwrap().remove(k)?;
+        self.syncer.after_write()
+    }
+
+    ///
+    /// Sets a key that reads as absent once `ttl` has passed
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use std::time::Duration;
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ///
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    ///
+    /// store.set_with_ttl(b"session".to_vec(), b"abc".to_vec(), Duration::from_secs(60)).unwrap();
+    /// assert!(store.ttl(b"session").unwrap().unwrap() <= Duration::from_secs(60));
+    ///
+    /// store.persist(b"session").unwrap();
+    /// assert_eq!(store.ttl(b"session").unwrap(), None);
+    /// ```
+    fn set_with_ttl(&self, k: Vec<u8>, v: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .set(k, v, Some(expiry::deadline(ttl)))?;
+        self.syncer.after_write()
+    }
+
+    fn expire(&self, k: &[u8], ttl: Duration) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .set_expiry(k, Some(expiry::deadline(ttl)))?;
+        self.syncer.after_write()
+    }
+
+    fn ttl(&self, k: &[u8]) -> Result<Option<Duration>> {
+        match self.shared.lookup(k) {
+            None => Err(KvsError::KeyNotFound),
+            Some(pointer) => Ok(pointer.expires_at.map(expiry::remaining)),
+        }
+    }
+
+    fn persist(&self, k: &[u8]) -> Result<()> {
+        self.writer.lock().unwrap().set_expiry(k, None)?;
+        self.syncer.after_write()
+    }
+
+    ///
+    /// Applies a batch of writes all-or-nothing
+    /// ```
+    /// use kvs::{KvStore, KvsEngine, KvsError, WriteBatch};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ///
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("from".to_owned(), "10".to_owned()).unwrap();
+    ///
+    /// let mut batch = WriteBatch::new();
+    /// batch.remove("from").set("to", "10");
+    /// store.write_batch(&batch).unwrap();
+    /// assert_eq!(store.get("to".to_owned()).unwrap(), Some("10".to_owned()));
+    ///
+    /// // "from" is gone, so this batch fails and "to" keeps its value
+    /// batch = WriteBatch::new();
+    /// batch.set("to", "20").remove("from");
+    /// assert!(matches!(store.write_batch(&batch), Err(KvsError::KeyNotFound)));
+    /// assert_eq!(store.get("to".to_owned()).unwrap(), Some("10".to_owned()));
+    /// ```
+    fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        self.writer.lock().unwrap().write_batch(batch)?;
+        self.syncer.after_write()
+    }
+
+    ///
+    /// Swaps a value only if it is the expected one
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ///
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    ///
+    /// // only the first worker gets to claim the job
+    /// assert!(store.set_if_absent(b"job1".to_vec(), b"worker1".to_vec()).unwrap());
+    /// assert!(!store.set_if_absent(b"job1".to_vec(), b"worker2".to_vec()).unwrap());
+    ///
+    /// let swapped = store
+    ///     .compare_and_swap(b"job1".to_vec(), Some(b"worker1".to_vec()), None)
+    ///     .unwrap();
+    /// assert!(swapped);
+    /// assert_eq!(store.get_bytes(b"job1").unwrap(), None);
+    /// ```
+    ///
+    /// Counts up and down atomically
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ///
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    ///
+    /// assert_eq!(store.incr(b"hits".to_vec()).unwrap(), 1);
+    /// assert_eq!(store.incr_by(b"hits".to_vec(), 10).unwrap(), 11);
+    /// assert_eq!(store.decr(b"hits".to_vec()).unwrap(), 10);
+    /// assert_eq!(store.get("hits".to_owned()).unwrap(), Some("10".to_owned()));
+    /// ```
+    fn incr_by(&self, k: Vec<u8>, delta: i64) -> Result<i64> {
+        let value = self.writer.lock().unwrap().incr_by(k, delta)?;
+        self.syncer.after_write()?;
+        Ok(value)
+    }
+
+    fn compare_and_swap(
+        &self,
+        k: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let swapped = self
+            .writer
+            .lock()
+            .unwrap()
+            .compare_and_swap(k, expected, new)?;
+        if swapped {
+            self.syncer.after_write()?;
+        }
+        Ok(swapped)
+    }
+
+    ///
+    /// Iterates over a range of keys in order
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ///
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// for key in ["b", "a", "c"] {
+    ///     store.set(key.to_owned(), key.to_uppercase()).unwrap();
+    /// }
+    ///
+    /// let pairs: Vec<_> = store
+    ///     .scan(b"b".to_vec().., None)
+    ///     .unwrap()
+    ///     .collect::<kvs::Result<_>>()
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     pairs,
+    ///     vec![(b"b".to_vec(), b"B".to_vec()), (b"c".to_vec(), b"C".to_vec())]
+    /// );
+    /// ```
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<KvPairs> {
+        // the index panics on a range that ends before it starts
+        if is_empty_range(range.start_bound(), range.end_bound()) {
+            return Ok(Box::new(iter::empty()));
+        }
+        Ok(Box::new(KvStoreScan {
+            reader: self.reader.clone(),
+            next: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            keys: VecDeque::new(),
+            remaining: limit.unwrap_or(usize::MAX),
+        }))
+    }
+}
+
+impl KvStore {
+    /// Used to create a new key-value store in a randomly named directory
+    /// under the system temporary directory
+    pub fn new() -> Result<Self> {
+        let rand_string: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(30)
+            .map(char::from)
+            .collect();
+
+        KvStore::open(&env::temp_dir().join(format!("kvs-{}", rand_string)))
+    }
+
+    /// Opens the KvStore at the given location
+    pub fn open(path: &Path) -> Result<Self> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens the KvStore at the given directory with the given options
+    pub fn open_with_options(path: &Path, options: KvStoreOptions) -> Result<Self> {
+        let fs = Arc::clone(&options.filesystem);
+        fs.create_dir_all(path)?;
+        let path = path.to_owned();
+        let lock = data_dir::claim(&*fs, &path, "kvs")?;
+        remove_temp_files(&*fs, &path)?;
+
+        let ids = segment_ids(&*fs, &path)?;
+        if ids.is_empty() && fs.exists(&path.join(LEGACY_LOG_FILE)) {
+            return Err(KvsError::UnsupportedFormat(format!(
+                "{} holds a log from before segments, upgrade it with kvs-migrate",
+                path.display()
+            )));
+        }
+        // without a usable hint every segment is replayed
+        let (mut index, mut segments, tail) = load_hint(&*fs, &path, &ids).unwrap_or_default();
+        let mut torn_write = None;
+        for (i, &id) in ids.iter().enumerate() {
+            if id < tail {
+                continue;
+            }
+            // a store that failed part way can leave records behind that
+            // never got synced, and nothing served after open may be lost to
+            // a crash
+            if options.durability != Durability::Never {
+                fs.open(&segment_path(&path, id))?.sync_data()?;
+            }
+            let torn_offset = match load_segment(&*fs, &path, id, &mut index, &mut segments)? {
+                None => continue,
+                Some(offset) => offset,
+            };
+            // only the segment that was being appended to can end in a
+            // half-written record, anywhere else it means the log is damaged
+            if i + 1 != ids.len() {
+                return Err(KvsError::Corruption {
+                    segment: id,
+                    offset: torn_offset,
+                });
+            }
+            let file = fs.append(&segment_path(&path, id))?;
+            let len = file.size()?;
+            file.set_len(torn_offset)?;
+            file.sync_all()?;
+            torn_write = Some(TornWrite {
+                segment: id,
+                offset: torn_offset,
+                discarded_bytes: len - torn_offset,
+            });
+        }
+
+        let active = segments.keys().next_back().cloned().unwrap_or(0);
+        segments.entry(active).or_insert_with(SegmentStats::new);
+        let writer = open_segment_writer(&*fs, &path, active)?;
+        let sync_handle = Arc::new(Mutex::new(writer.get_ref().try_clone()?));
+        let syncer = {
+            let sync_handle = Arc::clone(&sync_handle);
+            Syncer::new(options.durability, move || {
+                sync_handle.lock().unwrap().sync_data()?;
+                Ok(())
+            })
+        };
+
+        let shared = Arc::new(Shared {
+            fs,
+            dir: path,
+            index: RwLock::new(index),
+            segments: Mutex::new(segments),
+            generation: AtomicU64::new(0),
+            compacting: AtomicBool::new(false),
+            compact_calls: Mutex::new(()),
+            _lock: lock,
+        });
+        let writer = KvStoreWriter {
+            shared: Arc::clone(&shared),
+            reader: KvStoreReader::new(Arc::clone(&shared)),
+            writer,
+            active,
+            options,
+            sync_handle,
+            sync_on_seal: syncer.policy() != Durability::Never,
+            compaction: None,
+            failed: None,
+        };
+        Ok(KvStore {
+            reader: KvStoreReader::new(Arc::clone(&shared)),
+            shared,
+            writer: Arc::new(Mutex::new(writer)),
+            syncer: Arc::new(syncer),
+            torn_write,
+        })
+    }
+
+    /// Returns the partially written record that was cut off the end of the
+    /// log when the store was opened, if there was one
+    pub fn torn_write(&self) -> Option<TornWrite> {
+        self.torn_write
+    }
+
+    /// Seals the active segment and rewrites the live records of every sealed
+    /// segment that is mostly garbage, expired values included, into a single
+    /// new segment, waiting for the rewrite to finish. A compaction that is
+    /// already running is waited for first. Other writers are only held up
+    /// while the active segment is sealed.
+    ///
+    /// Every compaction ends by writing a hint, a snapshot of the index that
+    /// lets the next open skip replaying the sealed segments.
+    pub fn compact(&self) -> Result<()> {
+        let _calls = self.shared.compact_calls.lock().unwrap();
+        let compaction = loop {
+            let mut writer = self.writer.lock().unwrap();
+            writer.check_writable()?;
+            match writer.compaction.take() {
+                Some(running) => {
+                    drop(writer);
+                    let result = join_compaction(Some(running));
+                    self.writer.lock().unwrap().track(result)?;
+                }
+                None => {
+                    self.shared.sweep_expired();
+                    let started = writer.start_compaction();
+                    writer.track(started)?;
+                    break writer.compaction.take();
+                }
+            }
+        };
+        let result = join_compaction(compaction);
+        self.writer.lock().unwrap().track(result)
+    }
+}
+
+/// Reads records through a set of file handles owned by one KvStore clone
+struct KvStoreReader {
+    shared: Arc<Shared>,
+    handles: Mutex<ReaderHandles>,
+}
+
+#[derive(Default)]
+struct ReaderHandles {
+    files: HashMap<u64, BufReader<Box<dyn FsFile>>>,
+    /// value of `Shared::generation` when `files` was last pruned
+    generation: u64,
+}
+
+impl KvStoreReader {
+    fn new(shared: Arc<Shared>) -> Self {
+        KvStoreReader {
+            shared,
+            handles: Mutex::new(ReaderHandles::default()),
+        }
+    }
+
+    /// Reads the value of `k`, or `None` if it is not set or has expired
+    fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut pointer = match self.shared.lookup(k) {
+            None => return Ok(None),
+            Some(pointer) => pointer,
+        };
+        loop {
+            match self.read_command(pointer) {
+                Ok(MPCommand::Set { value, .. }) => return Ok(Some(value)),
+                Ok(_) => return Err(KvsError::UnexpectedCommand),
+                Err(err) => {
+                    // compaction may have moved the record and deleted its
+                    // segment between the lookup and the read
+                    let not_found =
+                        matches!(&err, KvsError::Io(err) if err.kind() == io::ErrorKind::NotFound);
+                    match self.shared.lookup(k) {
+                        None if not_found => return Ok(None),
+                        Some(moved) if not_found && moved != pointer => pointer = moved,
+                        _ => return Err(err),
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_command(&self, pointer: LogPointer) -> Result<MPCommand> {
+        let mut handles = self.handles.lock().unwrap();
+        let generation = self.shared.generation.load(Ordering::SeqCst);
+        if generation != handles.generation {
+            let segments = self.shared.segments.lock().unwrap();
+            handles.files.retain(|id, _| segments.contains_key(id));
+            handles.generation = generation;
+        }
+
+        let reader = match handles.files.entry(pointer.segment) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let path = segment_path(&self.shared.dir, pointer.segment);
+                let file = self.shared.fs.open(&path)?;
+                entry.insert(BufReader::new(file))
+            }
+        };
+        reader.seek(SeekFrom::Start(pointer.offset))?;
+        let len = pointer.len.try_into().map_err(|_| KvsError::Corruption {
+            segment: pointer.segment,
+            offset: pointer.offset,
+        })?;
+        let mut record = vec![0u8; len];
+        reader.read_exact(&mut record)?;
+        decode_record(&record, pointer.segment, pointer.offset)
+    }
+}
+
+impl Clone for KvStoreReader {
+    /// Clones start out with their own, empty set of file handles
+    fn clone(&self) -> Self {
+        KvStoreReader::new(Arc::clone(&self.shared))
+    }
+}
+
+/// Walks a range of the index, reading values as it goes
+///
+/// Keys are taken from the index a batch at a time so that a long scan holds
+/// up writers for no longer than a short one.
+struct KvStoreScan {
+    reader: KvStoreReader,
+    /// where the next batch of keys starts
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    keys: VecDeque<Vec<u8>>,
+    remaining: usize,
+}
+
+impl KvStoreScan {
+    const BATCH: usize = 64;
+
+    /// Takes the next batch of keys from the index, returning false at the
+    /// end of the range
+    fn fill(&mut self) -> bool {
+        let index = self.reader.shared.index.read().unwrap();
+        let batch = index
+            .range((self.next.clone(), self.end.clone()))
+            .filter(|(_, pointer)| !expiry::is_expired(pointer.expires_at))
+            .take(Self::BATCH)
+            .map(|(key, _)| key.clone());
+        self.keys.extend(batch);
+        match self.keys.back() {
+            Some(last) => {
+                self.next = Bound::Excluded(last.clone());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Returns true if no key can fall between `start` and `end`
+fn is_empty_range(start: Bound<&Vec<u8>>, end: Bound<&Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start >= end,
+        _ => false,
+    }
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            let key = match self.keys.pop_front() {
+                Some(key) => key,
+                None if self.fill() => continue,
+                None => return None,
+            };
+            // the key may have been removed since its batch was taken
+            match self.reader.get(&key) {
+                Ok(None) => continue,
+                Ok(Some(value)) => {
+                    self.remaining -= 1;
+                    return Some(Ok((key, value)));
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        None
+    }
+}
+
+/// The single writer of a KvStore, shared by all of its clones
+struct KvStoreWriter {
+    shared: Arc<Shared>,
+    /// reads back values whose expiry changes
+    reader: KvStoreReader,
+    writer: BufWriter<Box<dyn FsFile>>,
+    active: u64,
+    options: KvStoreOptions,
+    /// second handle on the active segment that the syncer syncs through
+    sync_handle: Arc<Mutex<Box<dyn FsFile>>>,
+    sync_on_seal: bool,
+    compaction: Option<JoinHandle<Result<()>>>,
+    /// set once a write to the log or a compaction fails part way. The log
+    /// may then end in a partial record, or hold segments the bookkeeping no
+    /// longer knows about, which only a reopen sorts out.
+    failed: Option<String>,
+}
+
+impl KvStoreWriter {
+    fn set(&mut self, k: Vec<u8>, v: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let pointer = self.append(&MPCommand::Set {
+            key: k.clone(),
+            value: v,
+            expires_at,
+        })?;
+        let old = self.shared.index.write().unwrap().insert(k, pointer);
+        if let Some(old) = old {
+            self.shared.mark_stale(old);
+        }
+        self.rotate_if_full()
+    }
+
+    /// Rewrites the value of `k` with a new expiry
+    fn set_expiry(&mut self, k: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let unchanged = match self.shared.lookup(k) {
+            None => return Err(KvsError::KeyNotFound),
+            Some(pointer) => pointer.expires_at == expires_at,
+        };
+        if unchanged {
+            return Ok(());
+        }
+        // the key cannot change under us since every write goes through here
+        match self.reader.get(k)? {
+            None => Err(KvsError::KeyNotFound),
+            Some(value) => self.set(k.to_vec(), value, expires_at),
+        }
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Result<()> {
+        if self.shared.lookup(k).is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        let pointer = self.append(&MPCommand::Rm { key: k.to_vec() })?;
+        let old = self.shared.index.write().unwrap().remove(k);
+        if let Some(old) = old {
+            self.shared.mark_stale(old);
+        }
+        // the tombstone itself is garbage as far as compaction is concerned
+        self.shared.mark_stale(pointer);
+        self.rotate_if_full()
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        k: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        // the value cannot change between the read and the write since every
+        // write goes through here
+        let current = self.reader.get(&k)?;
+        if current != expected {
+            return Ok(false);
+        }
+        match (current, new) {
+            (_, Some(new)) => self.set(k, new, None)?,
+            (Some(_), None) => self.remove(&k)?,
+            (None, None) => (),
+        }
+        Ok(true)
+    }
+
+    fn incr_by(&mut self, k: Vec<u8>, delta: i64) -> Result<i64> {
+        let current = self.reader.get(&k)?;
+        // a key that expired after the read counts as missing
+        let (current, expires_at) = match self.shared.lookup(&k) {
+            Some(pointer) => (current, pointer.expires_at),
+            None => (None, None),
+        };
+        let value = add_to_counter(current.as_deref(), delta)?;
+        self.set(k, value.to_string().into_bytes(), expires_at)?;
+        Ok(value)
+    }
+
+    /// Appends the writes of `batch` between begin and commit markers, so
+    /// that replay skips all of them unless the commit made it to disk, and
+    /// applies them to the index in one step
+    fn write_batch(&mut self, batch: &WriteBatch) -> Result<()> {
+        let found =
+            batch.removals_find_keys(|key| Ok::<_, KvsError>(self.shared.lookup(key).is_some()))?;
+        if !found {
+            return Err(KvsError::KeyNotFound);
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut commands = Vec::with_capacity(batch.len() + 2);
+        commands.push(MPCommand::BatchBegin {
+            len: batch.len() as u64,
+        });
+        commands.extend_from_slice(batch.commands());
+        commands.push(MPCommand::BatchCommit);
+        let pointers = self.append_all(&commands)?;
+        {
+            let mut index = self.shared.index.write().unwrap();
+            for (command, pointer) in commands.into_iter().zip(pointers) {
+                let old = match command {
+                    MPCommand::Set { key, .. } => index.insert(key, pointer),
+                    MPCommand::Rm { key } => {
+                        self.shared.mark_stale(pointer);
+                        index.remove(&key)
+                    }
+                    // the markers are garbage as soon as the batch is in
+                    _ => {
+                        self.shared.mark_stale(pointer);
+                        None
+                    }
+                };
+                if let Some(old) = old {
+                    self.shared.mark_stale(old);
+                }
+            }
+        }
+        self.rotate_if_full()
+    }
+
+    /// Serializes a command, appends it to the active segment and returns where
+    /// it was written
+    fn append(&mut self, command: &MPCommand) -> Result<LogPointer> {
+        let mut pointers = self.append_all(slice::from_ref(command))?;
+        Ok(pointers.remove(0))
+    }
+
+    /// Appends several commands to the active segment in a single write
+    fn append_all(&mut self, commands: &[MPCommand]) -> Result<Vec<LogPointer>> {
+        self.check_writable()?;
+        let records = commands
+            .iter()
+            .map(encode_record)
+            .collect::<Result<Vec<_>>>()?;
+        let pointers = {
+            let mut segments = self.shared.segments.lock().unwrap();
+            let stats = segments
+                .entry(self.active)
+                .or_insert_with(SegmentStats::new);
+            commands
+                .iter()
+                .zip(&records)
+                .map(|(command, record)| {
+                    let pointer = LogPointer {
+                        segment: self.active,
+                        offset: stats.len,
+                        len: record.len() as u64,
+                        expires_at: expiry_of(command),
+                    };
+                    stats.len += pointer.len;
+                    pointer
+                })
+                .collect()
+        };
+        let written = self
+            .writer
+            .write_all(&records.concat())
+            .and_then(|()| self.writer.flush());
+        if written.is_err() {
+            // what is still buffered must not reach the log when the writer
+            // is dropped
+            if let Ok(file) = self.writer.get_ref().try_clone() {
+                let _discarded = mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+            }
+        }
+        self.track(written.map_err(KvsError::from))?;
+        Ok(pointers)
+    }
+
+    /// Fails once an earlier failure has left the log in an unknown state
+    fn check_writable(&self) -> Result<()> {
+        match &self.failed {
+            None => Ok(()),
+            Some(err) => Err(KvsError::LogUnwritable(err.clone())),
+        }
+    }
+
+    /// Refuses further writes if `result` is a failure
+    fn track<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(err) = &result {
+            self.failed.get_or_insert_with(|| err.to_string());
+        }
+        result
+    }
+
+    /// Seals the active segment once it is full, kicking off a background
+    /// compaction if any sealed segment has become mostly garbage
+    fn rotate_if_full(&mut self) -> Result<()> {
+        let result = self.try_rotate();
+        self.track(result)
+    }
+
+    fn try_rotate(&mut self) -> Result<()> {
+        {
+            let segments = self.shared.segments.lock().unwrap();
+            let len = segments.get(&self.active).map_or(0, |stats| stats.len);
+            if len < self.options.max_segment_bytes {
+                return Ok(());
+            }
+        }
+        self.shared.sweep_expired();
+        let has_garbage = {
+            let segments = self.shared.segments.lock().unwrap();
+            // the segment being sealed counts as a candidate too
+            segments
+                .range(..=self.active)
+                .any(|(_, stats)| stats.is_compactable())
+        };
+
+        let compacting = self.shared.compacting.load(Ordering::SeqCst);
+        if has_garbage && !compacting {
+            // surface the failure of a previous compaction to this writer
+            self.finish_compaction()?;
+            self.start_compaction()
+        } else {
+            self.start_segment(self.active + 1)
+        }
+    }
+
+    /// Makes `id` the active segment
+    fn start_segment(&mut self, id: u64) -> Result<()> {
+        self.writer.flush()?;
+        // a sealed segment is never appended to again, so make sure it is
+        // complete on disk before moving on unless syncing is off entirely
+        if self.sync_on_seal {
+            self.writer.get_ref().sync_data()?;
+        }
+        self.writer = open_segment_writer(&*self.shared.fs, &self.shared.dir, id)?;
+        *self.sync_handle.lock().unwrap() = self.writer.get_ref().try_clone()?;
+        self.active = id;
+        self.shared
+            .segments
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_insert_with(SegmentStats::new);
+        Ok(())
+    }
+
+    /// Seals the active segment and hands the compactable sealed segments to
+    /// a background thread
+    fn start_compaction(&mut self) -> Result<()> {
+        // reserve the id between the sealed segments and the new active
+        // segment for the compacted output so replay order is preserved
+        let output = self.active + 1;
+        self.start_segment(output + 1)?;
+
+        let candidates = compaction_candidates(
+            &self.shared.segments.lock().unwrap(),
+            output,
+            self.options.max_segment_bytes,
+        );
+        // the index holds exactly the records of the sealed segments now,
+        // which is what the hint written after the compaction describes
+        let mut snapshot = self.shared.index.read().unwrap().clone();
+        let shared = Arc::clone(&self.shared);
+        let active = Arc::clone(&self.sync_handle);
+        self.shared.compacting.store(true, Ordering::SeqCst);
+        self.compaction = Some(thread::spawn(move || {
+            let result = compact_segments(&shared, &candidates, output, &active, &mut snapshot)
+                .and_then(|()| write_hint(&shared, snapshot, output + 1));
+            shared.compacting.store(false, Ordering::SeqCst);
+            result
+        }));
+        Ok(())
+    }
+
+    /// Waits for the running compaction, if any, and returns its outcome
+    fn finish_compaction(&mut self) -> Result<()> {
+        join_compaction(self.compaction.take())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        // let a running compaction swap in its output before the store goes
+        // away; anything it left half done is redone on the next compaction
+        let _ = self.finish_compaction();
+    }
+}
+
+fn join_compaction(compaction: Option<JoinHandle<Result<()>>>) -> Result<()> {
+    match compaction {
+        None => Ok(()),
+        Some(handle) => handle
+            .join()
+            .map_err(|_| KvsError::Background("compaction thread panicked".to_owned()))?,
+    }
+}
+
+/// Picks the sealed segments below `before` to compact, oldest first,
+/// stopping once the live data they hold would fill a segment
+fn compaction_candidates(
+    segments: &BTreeMap<u64, SegmentStats>,
+    before: u64,
+    max_segment_bytes: u64,
+) -> Vec<u64> {
+    let mut live_bytes = 0;
+    let mut candidates = vec![];
+    for (id, stats) in segments.range(..before) {
+        if !stats.is_compactable() {
+            continue;
+        }
+        live_bytes += stats.len - stats.stale;
+        if !candidates.is_empty() && live_bytes > max_segment_bytes {
+            break;
+        }
+        candidates.push(*id);
+    }
+    candidates
+}
+
+/// Copies the live records of `candidates` into segment `output`, points the
+/// index at the copies and deletes the old segments. Runs alongside readers
+/// and the writer: the index only switches over once the output is complete
+/// and on disk, in a single step under the index write lock. `snapshot` is
+/// brought in line with the output the same way. `active` is a handle on the
+/// active segment, synced before the old segments go away.
+///
+/// The output is written under a temporary name in the store directory and
+/// only renamed into place once it and the directory are synced, so a crash
+/// leaves either the old segments alone or the old segments and a complete
+/// output, which replay to the same index. Temporary files are removed by
+/// the next open.
+fn compact_segments(
+    shared: &Shared,
+    candidates: &[u64],
+    output: u64,
+    active: &Mutex<Box<dyn FsFile>>,
+    snapshot: &mut Index,
+) -> Result<()> {
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    // a tombstone only has to survive if an older segment that is not being
+    // compacted could still hold a value for its key
+    let oldest_retained = shared
+        .segments
+        .lock()
+        .unwrap()
+        .keys()
+        .find(|id| !candidates.contains(id))
+        .cloned();
+
+    let output_path = segment_path(&shared.dir, output);
+    let tmp_path = output_path.with_extension(COMPACT_EXTENSION);
+    let fs = &*shared.fs;
+    let mut writer = BufWriter::new(fs.create(&tmp_path)?);
+    writer.write_all(&segment_header())?;
+    let mut output_len = SEGMENT_HEADER_LEN;
+    let mut moved: Vec<(Vec<u8>, LogPointer, LogPointer)> = vec![];
+    let mut expired: Vec<(Vec<u8>, LogPointer)> = vec![];
+
+    for &id in candidates {
+        let mut scanner = SegmentScanner::open(fs, &shared.dir, id)?;
+        loop {
+            let (command, record, pointer) = match scanner.next()? {
+                Scanned::Record {
+                    command,
+                    bytes,
+                    pointer,
+                } => (command, bytes, pointer),
+                Scanned::End => break,
+                // sealed segments are never legitimately cut short
+                Scanned::Torn => {
+                    return Err(KvsError::Corruption {
+                        segment: id,
+                        offset: scanner.offset,
+                    })
+                }
+            };
+
+            let older_retained = oldest_retained.is_some_and(|retained| retained < id);
+            let record = {
+                let index = shared.index.read().unwrap();
+                match command {
+                    MPCommand::Set { key, .. } if expiry::is_expired(pointer.expires_at) => {
+                        // an expired value is dropped like a removed one, but
+                        // it may be the only thing shadowing an older value
+                        let latest = match index.get(&key) {
+                            None => true,
+                            Some(indexed) => *indexed == pointer,
+                        };
+                        if latest {
+                            expired.push((key.clone(), pointer));
+                        }
+                        if !latest || !older_retained {
+                            continue;
+                        }
+                        encode_record(&MPCommand::Rm { key })?
+                    }
+                    MPCommand::Set { key, .. } => {
+                        if index.get(&key) != Some(&pointer) {
+                            continue;
+                        }
+                        let new_pointer = LogPointer {
+                            segment: output,
+                            offset: output_len,
+                            ..pointer
+                        };
+                        moved.push((key, pointer, new_pointer));
+                        record
+                    }
+                    MPCommand::Rm { key } => {
+                        if index.contains_key(&key) || !older_retained {
+                            continue;
+                        }
+                        record
+                    }
+                    // batches in sealed segments are committed, so their
+                    // writes can be copied on their own
+                    MPCommand::BatchBegin { .. } | MPCommand::BatchCommit => continue,
+                    _ => {
+                        return Err(KvsError::UnexpectedCommand);
+                    }
+                }
+            };
+
+            writer.write_all(&record)?;
+            output_len += record.len() as u64;
+        }
+    }
+    writer.flush()?;
+    if output_len == SEGMENT_HEADER_LEN {
+        // nothing survived, so there is no output to keep
+        drop(writer);
+        fs.remove_file(&tmp_path)?;
+    } else {
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        fs.rename(&tmp_path, &output_path)?;
+        fs.sync_dir(&shared.dir)?;
+    }
+
+    for (key, old_pointer, new_pointer) in &moved {
+        if snapshot.get(key) == Some(old_pointer) {
+            snapshot.insert(key.clone(), *new_pointer);
+        }
+    }
+    // what is left in the old segments was overwritten after the snapshot, so
+    // the segments replayed after the hint set it anyway
+    snapshot.retain(|_, pointer| !candidates.contains(&pointer.segment));
+
+    {
+        let mut index = shared.index.write().unwrap();
+        // records overwritten or removed while the copy was running are
+        // already garbage in the output, as is its header
+        let mut stale = SEGMENT_HEADER_LEN;
+        for (key, old_pointer, new_pointer) in moved {
+            if index.get(&key) == Some(&old_pointer) {
+                index.insert(key, new_pointer);
+            } else {
+                stale += new_pointer.len;
+            }
+        }
+        for (key, pointer) in expired {
+            if index.get(&key) == Some(&pointer) {
+                index.remove(&key);
+            }
+        }
+        let mut segments = shared.segments.lock().unwrap();
+        for id in candidates {
+            segments.remove(id);
+        }
+        if output_len > SEGMENT_HEADER_LEN {
+            segments.insert(
+                output,
+                SegmentStats {
+                    len: output_len,
+                    stale,
+                },
+            );
+        }
+    }
+    shared.generation.fetch_add(1, Ordering::SeqCst);
+
+    // records left out of the output because the index had moved on may have
+    // been replaced by writes that are not synced yet, and those have to be
+    // on disk before the old values go
+    active.lock().unwrap().sync_data()?;
+    for id in candidates {
+        fs.remove_file(&segment_path(&shared.dir, *id))?;
+    }
+    // the hint written next expects the old segments to be gone
+    fs.sync_dir(&shared.dir)?;
+    Ok(())
+}
+
+/// Writes `snapshot`, the index as it stood when segment `tail` was started,
+/// to the hint file. The hint is written in full under another name first,
+/// so a crash leaves either the old hint or the new one.
+fn write_hint(shared: &Shared, snapshot: Index, tail: u64) -> Result<()> {
+    let segments = shared
+        .segments
+        .lock()
+        .unwrap()
+        .range(..tail)
+        .map(|(id, stats)| (*id, stats.len))
+        .collect();
+    let entries = snapshot
+        .into_iter()
+        .map(|(key, pointer)| HintEntry {
+            key,
+            segment: pointer.segment,
+            offset: pointer.offset,
+            len: pointer.len,
+            expires_at: pointer.expires_at,
+        })
+        .collect();
+    let hint = Hint {
+        tail,
+        segments,
+        entries,
+    };
+    let mut payload = vec![];
+    hint.serialize(&mut Serializer::new(&mut payload))?;
+
+    let tmp_path = shared.dir.join(HINT_TMP_FILE);
+    let mut file = shared.fs.create(&tmp_path)?;
+    file.write_all(&frame(&payload))?;
+    file.sync_all()?;
+    shared.fs.rename(&tmp_path, &shared.dir.join(HINT_FILE))?;
+    shared.fs.sync_dir(&shared.dir)?;
+    Ok(())
+}
+
+/// Loads the index and segment bookkeeping from the hint file, along with the
+/// first segment the hint does not cover. Returns `None` if there is no hint,
+/// or if it is damaged or does not match the segments in `ids`.
+fn load_hint(
+    fs: &dyn Filesystem,
+    dir: &Path,
+    ids: &[u64],
+) -> Option<(Index, BTreeMap<u64, SegmentStats>, u64)> {
+    let record = fs.read(&dir.join(HINT_FILE)).ok()?;
+    let hint: Hint = rmp_serde::decode::from_read_ref(unframe(&record)?).ok()?;
+
+    // a compaction that died before writing its own hint leaves one that
+    // covers segments which are gone
+    let covered: Vec<u64> = ids.iter().cloned().filter(|id| *id < hint.tail).collect();
+    if !covered.iter().eq(hint.segments.iter().map(|(id, _)| id)) {
+        return None;
+    }
+    let mut segments = BTreeMap::new();
+    for &(id, len) in &hint.segments {
+        let mut file = fs.open(&segment_path(dir, id)).ok()?;
+        if file.size().ok()? != len {
+            return None;
+        }
+        // segments the hint skips still have to be in a format this kvs reads
+        let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+        file.read_exact(&mut header).ok()?;
+        check_segment_header(&header, id).ok()?;
+        // everything but the records the index points at is garbage
+        segments.insert(id, SegmentStats { len, stale: len });
+    }
+
+    let mut index = BTreeMap::new();
+    for entry in hint.entries {
+        let stats = segments.get_mut(&entry.segment)?;
+        if entry.offset.checked_add(entry.len)? > stats.len {
+            return None;
+        }
+        if expiry::is_expired(entry.expires_at) {
+            continue;
+        }
+        stats.stale = stats.stale.checked_sub(entry.len)?;
+        let pointer = LogPointer {
+            segment: entry.segment,
+            offset: entry.offset,
+            len: entry.len,
+            expires_at: entry.expires_at,
+        };
+        index.insert(entry.key, pointer);
+    }
+    Some((index, segments, hint.tail))
+}
+
+pub(crate) fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.{}", id, SEGMENT_EXTENSION))
+}
+
+/// Returns the ids of the segment files in `dir`, in ascending order
+pub(crate) fn segment_ids(fs: &dyn Filesystem, dir: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = vec![];
+    for path in fs.read_dir(dir)? {
+        if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Removes compaction outputs and hints that a crash left half written
+fn remove_temp_files(fs: &dyn Filesystem, dir: &Path) -> Result<()> {
+    for path in fs.read_dir(dir)? {
+        let unfinished = path.extension().is_some_and(|ext| ext == COMPACT_EXTENSION)
+            || path.file_name().is_some_and(|name| name == HINT_TMP_FILE);
+        if unfinished {
+            fs.remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Opens segment `id` for appending, starting it with a header if it is new
+fn open_segment_writer(
+    fs: &dyn Filesystem,
+    dir: &Path,
+    id: u64,
+) -> Result<BufWriter<Box<dyn FsFile>>> {
+    let mut file = fs.append(&segment_path(dir, id))?;
+    if file.size()? == 0 {
+        file.write_all(&segment_header())?;
+    }
+    // records synced into the segment are lost in a crash unless its
+    // directory entry is durable too, which a failed open may not have seen to
+    fs.sync_dir(dir)?;
+    Ok(BufWriter::new(file))
+}
+
+/// The header every segment written by this kvs starts with
+pub(crate) fn segment_header() -> Vec<u8> {
+    let mut header = SEGMENT_MAGIC.to_vec();
+    header.extend(FORMAT_VERSION.to_be_bytes());
+    header.extend(KNOWN_FLAGS.to_be_bytes());
+    header
+}
+
+/// Checks that a segment header was written in a format this kvs can read
+pub(crate) fn check_segment_header(header: &[u8], segment: u64) -> Result<()> {
+    let unsupported = |msg: String| Err(KvsError::UnsupportedFormat(msg));
+    if header.len() < SEGMENT_HEADER_LEN as usize || header[..4] != SEGMENT_MAGIC {
+        return unsupported(format!(
+            "segment {} has no format header, upgrade it with kvs-migrate",
+            segment
+        ));
+    }
+    let version = (&header[4..]).read_u32::<BigEndian>()?;
+    let flags = (&header[8..]).read_u32::<BigEndian>()?;
+    if version > FORMAT_VERSION {
+        return unsupported(format!(
+            "segment {} has format version {} but this kvs only reads up to {}",
+            segment, version, FORMAT_VERSION
+        ));
+    }
+    if version < FORMAT_VERSION {
+        return unsupported(format!(
+            "segment {} has format version {}, upgrade it with kvs-migrate",
+            segment, version
+        ));
+    }
+    if flags & !KNOWN_FLAGS != 0 {
+        return unsupported(format!(
+            "segment {} uses unknown feature flags {:#x}",
+            segment,
+            flags & !KNOWN_FLAGS
+        ));
+    }
+    Ok(())
+}
+
+/// Replays segment `id`, updating the index and the segment bookkeeping.
+/// Returns the offset of a partially written record or batch at the end of
+/// the segment if there is one.
+fn load_segment(
+    fs: &dyn Filesystem,
+    dir: &Path,
+    id: u64,
+    index: &mut Index,
+    segments: &mut BTreeMap<u64, SegmentStats>,
+) -> Result<Option<u64>> {
+    let mut scanner = SegmentScanner::open(fs, dir, id)?;
+    segments.entry(id).or_insert_with(SegmentStats::new);
+    // the begin marker and writes of a batch whose commit is still to come
+    let mut batch: Option<Vec<(MPCommand, LogPointer)>> = None;
+    loop {
+        let (command, pointer) = match scanner.next()? {
+            Scanned::Record {
+                command, pointer, ..
+            } => (command, pointer),
+            // nothing of a batch counts unless its commit is on disk
+            Scanned::End | Scanned::Torn if batch.is_some() => {
+                return Ok(batch.map(|records| records[0].1.offset));
+            }
+            Scanned::End => return Ok(None),
+            Scanned::Torn => return Ok(Some(scanner.offset)),
+        };
+
+        match (command, &mut batch) {
+            (command @ MPCommand::BatchBegin { .. }, None) => {
+                batch = Some(vec![(command, pointer)])
+            }
+            (command @ (MPCommand::Set { .. } | MPCommand::Rm { .. }), Some(records)) => {
+                records.push((command, pointer));
+            }
+            (command @ MPCommand::BatchCommit, Some(_)) => {
+                for (command, pointer) in batch.take().into_iter().flatten() {
+                    replay_command(command, pointer, index, segments)?;
+                }
+                replay_command(command, pointer, index, segments)?;
+            }
+            (command, None) => replay_command(command, pointer, index, segments)?,
+            (_, Some(_)) => return Err(KvsError::UnexpectedCommand),
+        }
+    }
+}
+
+/// Applies a single record read back from the log to the index and the
+/// segment bookkeeping
+fn replay_command(
+    command: MPCommand,
+    pointer: LogPointer,
+    index: &mut Index,
+    segments: &mut BTreeMap<u64, SegmentStats>,
+) -> Result<()> {
+    if let Some(stats) = segments.get_mut(&pointer.segment) {
+        stats.len = pointer.offset + pointer.len;
+    }
+
+    let stale = match command {
+        MPCommand::Set { key, .. } if expiry::is_expired(pointer.expires_at) => {
+            // an expired value is as good as removed
+            if let Some(stats) = segments.get_mut(&pointer.segment) {
+                stats.stale += pointer.len;
+            }
+            index.remove(&key)
+        }
+        MPCommand::Set { key, .. } => index.insert(key, pointer),
+        MPCommand::Rm { key } => {
+            // the tombstone is garbage along with the value it removed
+            if let Some(stats) = segments.get_mut(&pointer.segment) {
+                stats.stale += pointer.len;
+            }
+            index.remove(&key)
+        }
+        MPCommand::BatchBegin { .. } | MPCommand::BatchCommit => {
+            if let Some(stats) = segments.get_mut(&pointer.segment) {
+                stats.stale += pointer.len;
+            }
+            None
+        }
+        _ => {
+            return Err(KvsError::UnexpectedCommand);
+        }
+    };
+    if let Some(stale) = stale {
+        if let Some(stats) = segments.get_mut(&stale.segment) {
+            stats.stale += stale.len;
+        }
+    }
+    Ok(())
+}
+
+/// Returns when the key set by `command` expires
+fn expiry_of(command: &MPCommand) -> Option<u64> {
+    match command {
+        MPCommand::Set { expires_at, .. } => *expires_at,
+        _ => None,
+    }
+}
+
+/// Serializes a command into a record: a big-endian u64 payload length, a
+/// big-endian CRC32 of the payload and the payload itself
+pub(crate) fn encode_record(command: &MPCommand) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    command.serialize(&mut Serializer::new(&mut buf))?;
+    Ok(frame(&buf))
+}
+
+/// Prefixes `payload` with its length and checksum
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut record = (payload.len() as u64).to_be_bytes().to_vec();
+    record.extend(crc32fast::hash(payload).to_be_bytes());
+    record.extend(payload);
+    record
+}
+
+/// Returns the payload of a complete record, or `None` if its length or
+/// checksum do not match
+fn unframe(record: &[u8]) -> Option<&[u8]> {
+    if (record.len() as u64) < RECORD_HEADER_LEN {
+        return None;
+    }
+    let (header, payload) = record.split_at(RECORD_HEADER_LEN as usize);
+    let payload_len = (&header[..SIZE_OF_U64 as usize])
+        .read_u64::<BigEndian>()
+        .ok()?;
+    let checksum = (&header[SIZE_OF_U64 as usize..])
+        .read_u32::<BigEndian>()
+        .ok()?;
+    if payload.len() as u64 != payload_len || crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    Some(payload)
+}
+
+/// Checks and decodes a complete record read from `offset` in `segment`
+fn decode_record(record: &[u8], segment: u64, offset: u64) -> Result<MPCommand> {
+    let corruption = || KvsError::Corruption { segment, offset };
+    let payload = unframe(record).ok_or_else(corruption)?;
+    rmp_serde::decode::from_read_ref(payload).map_err(|_err| corruption())
+}
+
+/// What a `SegmentScanner` found at its current offset
+enum Scanned {
+    /// a complete record that passed its checksum
+    Record {
+        command: MPCommand,
+        bytes: Vec<u8>,
+        pointer: LogPointer,
+    },
+    /// the segment ends cleanly here
+    End,
+    /// the segment ends partway through the record starting here
+    Torn,
+}
+
+/// Walks the records of a segment file from the start
+struct SegmentScanner {
+    reader: BufReader<Box<dyn FsFile>>,
+    segment: u64,
+    offset: u64,
+    len: u64,
+}
+
+impl SegmentScanner {
+    /// Opens segment `segment` and checks its header. A segment too short to
+    /// hold a header is scanned from offset 0 and turns out torn.
+    fn open(fs: &dyn Filesystem, dir: &Path, segment: u64) -> Result<Self> {
+        let file = fs.open(&segment_path(dir, segment))?;
+        let len = file.size()?;
+        let mut reader = BufReader::new(file);
+        let mut offset = 0;
+        if len >= SEGMENT_HEADER_LEN {
+            let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+            reader.read_exact(&mut header)?;
+            check_segment_header(&header, segment)?;
+            offset = SEGMENT_HEADER_LEN;
+        }
+        Ok(SegmentScanner {
+            reader,
+            segment,
+            offset,
+            len,
+        })
+    }
+
+    fn next(&mut self) -> Result<Scanned> {
+        let remaining = self.len - self.offset;
+        if remaining == 0 {
+            return Ok(Scanned::End);
+        }
+        // a header that was never written in full
+        if self.offset < SEGMENT_HEADER_LEN || remaining < RECORD_HEADER_LEN {
+            return Ok(Scanned::Torn);
+        }
+
+        let mut bytes = vec![0u8; RECORD_HEADER_LEN as usize];
+        self.reader.read_exact(&mut bytes)?;
+        let payload_len = (&bytes[..SIZE_OF_U64 as usize]).read_u64::<BigEndian>()?;
+        if payload_len > remaining - RECORD_HEADER_LEN {
+            return Ok(Scanned::Torn);
+        }
+        let len =
+            (RECORD_HEADER_LEN + payload_len)
+                .try_into()
+                .map_err(|_| KvsError::Corruption {
+                    segment: self.segment,
+                    offset: self.offset,
+                })?;
+        bytes.resize(len, 0);
+        self.reader
+            .read_exact(&mut bytes[RECORD_HEADER_LEN as usize..])?;
+
+        let command = match decode_record(&bytes, self.segment, self.offset) {
+            Ok(command) => command,
+            // a bad checksum on the very last record is a write that never
+            // made it to disk in full rather than corruption
+            Err(_err) if self.offset + bytes.len() as u64 == self.len => return Ok(Scanned::Torn),
+            Err(err) => return Err(err),
+        };
+        let pointer = LogPointer {
+            segment: self.segment,
+            offset: self.offset,
+            len: bytes.len() as u64,
+            expires_at: expiry_of(&command),
+        };
+        self.offset += pointer.len;
+        Ok(Scanned::Record {
+            command,
+            bytes,
+            pointer,
+        })
+    }
+}
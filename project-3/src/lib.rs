@@ -2,355 +2,606 @@
 
 #![deny(missing_docs)]
 
-use byteorder::{BigEndian, ReadBytesExt};
-
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
-
 use serde::{Deserialize, Serialize};
-extern crate rmp_serde as rmps;
-
-use rmps::Serializer;
-
-use std::collections::HashMap;
-use std::fs::{self, OpenOptions};
-
-use std::io::{prelude::*, SeekFrom};
-use std::mem::size_of;
 
-use sled::{self, Db};
-use std::path::{Path, PathBuf};
-
-const SIZE_OF_U64: u64 = size_of::<u64>() as u64;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, Transactional,
+    UnabortableTransactionError,
+};
+use sled::{self, Batch, Db, IVec, Tree};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod batch;
+mod client;
+mod data_dir;
+mod durability;
+mod error;
+mod expiry;
+pub mod filesystem;
+mod kv_store;
+mod migrate;
+pub mod protocol;
+mod server;
+pub mod thread_pool;
+mod wire;
+
+pub use batch::WriteBatch;
+pub use client::KvsClient;
+use data_dir::DirLock;
+pub use durability::Durability;
+use durability::Syncer;
+pub use error::KvsError;
+use expiry::Sweeper;
+use filesystem::RealFs;
+pub use kv_store::{KvStore, KvStoreOptions, TornWrite, DEFAULT_MAX_SEGMENT_BYTES, FORMAT_VERSION};
+pub use migrate::{migrate, migrate_with_filesystem, Migration};
+pub use server::{serve_async, KvsServer, KvsServerBuilder};
 
 /// enum representing a command
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+///
+/// Keys and values are arbitrary bytes. They are encoded as msgpack binary,
+/// but strings written by older versions decode just as well.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum MPCommand {
     /// get command
     Get {
         /// key to get
-        key: String,
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
     },
     /// set command
     Set {
         /// key to set
-        key: String,
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
         /// value corresponding to key
-        value: String,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        /// when the key expires, in milliseconds since the Unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
     },
     /// rm command
     Rm {
         /// key to remove
-        key: String,
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// set command for a key that expires after a while
+    SetWithTtl {
+        /// key to set
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// value corresponding to key
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        /// milliseconds until the key expires
+        ttl_ms: u64,
+    },
+    /// expire command
+    Expire {
+        /// key to expire
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// milliseconds until the key expires
+        ttl_ms: u64,
+    },
+    /// ttl command
+    Ttl {
+        /// key to get the time to live of
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// persist command
+    Persist {
+        /// key to stop expiring
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// scan command, answered with a `protocol::ScanPage`
+    Scan {
+        /// first key to return
+        #[serde(with = "serde_bytes")]
+        start: Vec<u8>,
+        /// key to stop before, or `None` to scan to the last key
+        #[serde(with = "serde_bytes")]
+        end: Option<Vec<u8>>,
+        /// largest number of pairs to return
+        limit: u64,
+    },
+    /// start of a batch of writes that is applied all-or-nothing
+    BatchBegin {
+        /// number of writes between this and the commit
+        len: u64,
+    },
+    /// end of a batch of writes
+    BatchCommit,
+    /// compare-and-swap command
+    CompareAndSwap {
+        /// key to swap the value of
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// value the key must have, or `None` if it must be absent
+        #[serde(with = "serde_bytes")]
+        expected: Option<Vec<u8>>,
+        /// value to swap in, or `None` to remove the key
+        #[serde(with = "serde_bytes")]
+        new: Option<Vec<u8>>,
+    },
+    /// set command that only sets absent keys
+    SetIfAbsent {
+        /// key to set
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// value corresponding to key
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// set command that only sets present keys
+    SetIfPresent {
+        /// key to set
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// value corresponding to key
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// increment command
+    IncrBy {
+        /// key of the counter
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// amount to add, negative to decrement
+        delta: i64,
+    },
+    /// get command for several keys, answered with one entry per key
+    MGet {
+        /// keys to get
+        #[serde(with = "wire::keys")]
+        keys: Vec<Vec<u8>>,
+    },
+    /// set command for several pairs, applied all-or-nothing
+    MSet {
+        /// keys and the values to set them to
+        #[serde(with = "wire::pairs")]
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
     },
-}
-
-/// Main struct implementing key-value store functionality
-pub struct KvStore {
-    /// offset map
-    pub offset_map: HashMap<String, u64>,
-    path: PathBuf,
-    redundancies: u64,
 }
 
 /// Result type for KvStore
-pub type Result<T> = std::result::Result<T, failure::Error>;
+pub type Result<T> = std::result::Result<T, KvsError>;
+
+/// Key-value pairs returned by a scan
+///
+/// Pairs are read as the iterator advances, so writes made in the meantime
+/// may or may not show up.
+pub type KvPairs = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// Adds `delta` to a counter stored as decimal text, where a missing value
+/// counts as 0
+pub(crate) fn add_to_counter(current: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let current = match current {
+        None => 0,
+        Some(current) => std::str::from_utf8(current)
+            .ok()
+            .and_then(|current| current.parse::<i64>().ok())
+            .ok_or_else(|| KvsError::InvalidValue("value is not an integer".to_owned()))?,
+    };
+    current
+        .checked_add(delta)
+        .ok_or_else(|| KvsError::InvalidValue("increment would overflow".to_owned()))
+}
 
-impl KvsEngine for KvStore {
-    ///
-    /// Gets a value from the key-value store
-    /// ```
-    /// use kvs::KvStore;
-    /// use tempfile::TempDir;
-    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-
-    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
-    ///
-    /// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
-    /// assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
-    /// ```
-    fn get(&mut self, k: String) -> Result<Option<String>> {
-        {
-            let opt_offset = self.offset_map.get(&k).cloned();
-            match opt_offset {
-                None => Ok(None),
-                Some(offset) => {
-                    let mut file = OpenOptions::new().read(true).open(self.path.as_path())?;
-                    file.seek(SeekFrom::Start(offset as u64))?;
-                    let mut buf: [u8; SIZE_OF_U64 as usize] = [0; SIZE_OF_U64 as usize];
-                    file.read_exact(&mut buf)?;
-
-                    let record_len = (&buf[0..SIZE_OF_U64 as usize]).read_u64::<BigEndian>()?;
-                    let mut buf = vec![0u8; record_len.try_into()?];
-                    file.read_exact(&mut buf)?;
-                    let record: MPCommand = rmps::decode::from_read_ref(&buf)?;
-                    match record {
-                        MPCommand::Set { value, .. } => Ok(Some(value)),
-                        _ => Err(failure::err_msg("Did not find set command where expected")),
-                    }
-                }
-            }
+/// Returns the first key after every key that starts with `prefix`, or
+/// `None` if there is no such key
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
         }
     }
+    None
+}
 
-    /// Used to set key in store
-    fn set(&mut self, k: String, v: String) -> Result<()> {
-        {
-            let set_command = MPCommand::Set {
-                key: k.clone(),
-                value: v,
-            };
+/// defines the storage interface called by KvsServer
+///
+/// Engines are cheap to clone and every clone operates on the same store, so
+/// a server hands one clone to each thread that serves requests.
+///
+/// Keys and values are arbitrary bytes. The `String` methods are shorthands
+/// for text keys and values.
+///
+/// A key may be given a time to live, after which it reads as absent. Setting
+/// a key without one clears any expiry it had.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Set key-value pair in store
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// Gets a value from store
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Remove key from store
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+    /// Set key-value pair in store that expires after `ttl`
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    /// Makes an existing key expire after `ttl`
+    fn expire(&self, key: &[u8], ttl: Duration) -> Result<()>;
+    /// Returns the time left until key expires, or `None` if it never does
+    fn ttl(&self, key: &[u8]) -> Result<Option<Duration>>;
+    /// Stops an existing key from expiring
+    fn persist(&self, key: &[u8]) -> Result<()>;
+    /// Iterates over the key-value pairs in `range` in key order, yielding at
+    /// most `limit` of them
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<KvPairs>;
+    /// Applies every write of `batch`, or none of them if one fails
+    fn write_batch(&self, batch: &WriteBatch) -> Result<()>;
+    /// Sets key to `new`, or removes it if `new` is `None`, but only if its
+    /// value is `expected`, with `None` standing for an absent key. Returns
+    /// whether the swap happened.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Adds `delta` to the integer stored at key and returns the result. A
+    /// missing key counts as 0, and the key keeps any expiry it has.
+    fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64>;
+
+    /// Adds one to the integer stored at key and returns the result
+    fn incr(&self, key: Vec<u8>) -> Result<i64> {
+        self.incr_by(key, 1)
+    }
 
-            let mut buf = Vec::new();
-            set_command.serialize(&mut Serializer::new(&mut buf))?;
-            let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(&self.path)?;
+    /// Subtracts one from the integer stored at key and returns the result
+    fn decr(&self, key: Vec<u8>) -> Result<i64> {
+        self.incr_by(key, -1)
+    }
 
-            let buf_len = (buf.len() as u64).to_be_bytes();
+    /// Sets key to `value` unless it is already set, returning whether it was
+    /// set
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
 
-            let file_length = file.metadata()?.len();
-            if self.offset_map.contains_key(&k) {
-                self.redundancies += 1;
+    /// Sets key to `value` only if it is already set, returning whether it
+    /// was set
+    fn set_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        loop {
+            let current = match self.get_bytes(&key)? {
+                None => return Ok(false),
+                Some(current) => current,
+            };
+            // try again if the value changed since it was read
+            if self.compare_and_swap(key.clone(), Some(current), Some(value.clone()))? {
+                return Ok(true);
             }
-            self.offset_map.insert(k, file_length);
-            file.write_all(&buf_len).unwrap();
-            file.write_all(&buf).unwrap();
         }
-        // compact if redundancy level is high
-        if self.redundancies as f64 > (self.offset_map.len() as f64 * 3.0) {
-            self.compact()?;
+    }
+
+    /// Iterates over the key-value pairs whose keys start with `prefix`, in
+    /// key order
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvPairs> {
+        let end = match prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.scan((Bound::Included(prefix.to_vec()), end), None)
+    }
+
+    /// Set key-value pair in store
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+    /// Gets a value from store, failing with `KvsError::Utf8` if it is not text
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+    /// Remove key from store
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
+
+/// KvsEngine implementation using sled crate
+///
+/// Expiry deadlines live in a separate tree so that the main tree holds
+/// nothing but keys and values. Expired keys are deleted when they are read,
+/// and by a sweep on open and every `EXPIRY_SWEEP_INTERVAL` after that.
+#[derive(Clone)]
+pub struct SledEngine {
+    db: Db,
+    expiry: Tree,
+    syncer: Arc<Syncer>,
+    /// deletes expired keys that are never read again
+    _sweeper: Arc<Sweeper>,
+    /// keeps other stores out of the directory
+    _lock: Arc<DirLock>,
+}
+
+impl KvsEngine for SledEngine {
+    /// Gets a value from the key-value store
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.is_expired(key)? {
+            self.purge(key)?;
+            return Ok(None);
         }
+        Ok(self.db.get(key)?.map(|ivec| ivec.to_vec()))
+    }
 
-        Ok(())
+    /// Used to set key in store
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        (&*self.db, &self.expiry).transaction(|(db, expiry)| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })?;
+        self.syncer.after_write()
     }
 
     /// Used to remove key from store
-    fn remove(&mut self, k: String) -> Result<()> {
-        {
-            let opt_offset = self.offset_map.get(&k).cloned();
-            match opt_offset {
-                None => Err(failure::err_msg("Key not found")),
-                Some(_offset) => {
-                    let rm_command = MPCommand::Rm { key: k.clone() };
-
-                    let mut buf = Vec::new();
-                    rm_command.serialize(&mut Serializer::new(&mut buf))?;
-                    let mut file = OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .append(true)
-                        .open(&self.path)?;
-
-                    self.offset_map.remove(&k);
-                    self.redundancies += 2;
-
-                    let buf_len = (buf.len() as u64).to_be_bytes();
-
-                    file.write_all(&buf_len).unwrap();
-                    file.write_all(&buf).unwrap();
-                    Ok(())
-                }
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        (&*self.db, &self.expiry).transaction(|(db, expiry)| {
+            let deadline = expiry.remove(key)?;
+            match db.remove(key)? {
+                Some(_ivec) if !expiry::is_expired(decode_deadline(deadline)) => Ok(()),
+                _ => abort(KvsError::KeyNotFound),
             }
-        }
+        })?;
+        self.syncer.after_write()
     }
-}
 
-impl KvStore {
-    /// Opens the KvStore at the given location
-    /// Used to create a new key-value store at the given path
-    pub fn new() -> Result<Self> {
-        let rand_string: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(30)
-            .map(char::from)
-            .collect();
-
-        let path = Path::new(&rand_string);
-        if !path.exists() {
-            let _file = OpenOptions::new().create(true).write(true).open(&path)?;
-        }
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let deadline = expiry::deadline(ttl).to_be_bytes();
+        (&*self.db, &self.expiry).transaction(|(db, expiry)| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            expiry.insert(key.as_slice(), &deadline)?;
+            Ok(())
+        })?;
+        self.syncer.after_write()
+    }
 
-        let offset_map = HashMap::new();
-        Ok(KvStore {
-            path: path.to_owned(),
-            offset_map,
-            redundancies: 0,
-        })
+    fn expire(&self, key: &[u8], ttl: Duration) -> Result<()> {
+        let deadline = expiry::deadline(ttl).to_be_bytes();
+        (&*self.db, &self.expiry).transaction(|(db, expiry)| {
+            let old = decode_deadline(expiry.get(key)?);
+            if db.get(key)?.is_none() || expiry::is_expired(old) {
+                return abort(KvsError::KeyNotFound);
+            }
+            expiry.insert(key, &deadline)?;
+            Ok(())
+        })?;
+        self.syncer.after_write()
     }
 
-    /// Opens the KvStore at the given location
-    pub fn open(path: &Path) -> Result<Self> {
-        let path = if path.is_dir() {
-            path.join("my-file")
-        } else {
-            path.to_owned()
-        };
-        if !path.exists() {
-            let _file = OpenOptions::new().create(true).write(true).open(&path)?;
+    fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let deadline = decode_deadline(self.expiry.get(key)?);
+        if self.db.get(key)?.is_none() || expiry::is_expired(deadline) {
+            return Err(KvsError::KeyNotFound);
         }
+        Ok(deadline.map(expiry::remaining))
+    }
 
-        let mut offset_map = HashMap::new();
-
-        let mut offset: u64 = 0;
-        let mut redundancies: u64 = 0;
-        let mut file = OpenOptions::new().read(true).open(path.as_path())?;
-        loop {
-            let mut buf: [u8; SIZE_OF_U64 as usize] = [0; SIZE_OF_U64 as usize];
-            let read_len_result = file.read_exact(&mut buf);
-
-            match read_len_result {
-                Ok(()) => {}
-                Err(_err) => {
-                    // TODO distinguish end of file
-                    break;
-                }
+    fn persist(&self, key: &[u8]) -> Result<()> {
+        (&*self.db, &self.expiry).transaction(|(db, expiry)| {
+            let old = decode_deadline(expiry.remove(key)?);
+            if db.get(key)?.is_none() || expiry::is_expired(old) {
+                return abort(KvsError::KeyNotFound);
             }
+            Ok(())
+        })?;
+        self.syncer.after_write()
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<KvPairs> {
+        let expiry = self.expiry.clone();
+        let pairs = self
+            .db
+            .range(range)
+            .map(move |pair| {
+                let (key, value) = pair?;
+                let deadline = decode_deadline(expiry.get(&key)?);
+                Ok((!expiry::is_expired(deadline)).then(|| (key.to_vec(), value.to_vec())))
+            })
+            .filter_map(Result::transpose)
+            .take(limit.unwrap_or(usize::MAX));
+        Ok(Box::new(pairs))
+    }
 
-            let record_len = (&buf[0..SIZE_OF_U64 as usize]).read_u64::<BigEndian>()?;
-            let mut buf = vec![0u8; record_len.try_into()?];
-            file.read_exact(&mut buf)?;
-            let record: MPCommand = rmps::decode::from_read_ref(&buf)?;
-            match record {
-                MPCommand::Set { key, .. } => {
-                    if offset_map.contains_key(&key) {
-                        redundancies += 1;
-                    }
-                    offset_map.insert(key, offset);
+    fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        let mut writes = Batch::default();
+        let mut expiries = Batch::default();
+        for command in batch.commands() {
+            // every write of the batch leaves its key without an expiry
+            match command {
+                MPCommand::Set { key, value, .. } => {
+                    writes.insert(key.as_slice(), value.as_slice());
+                    expiries.remove(key.as_slice());
                 }
                 MPCommand::Rm { key } => {
-                    // one duplication for the redundant set, one for the unnecessary remove
-                    redundancies += 2;
-                    offset_map.remove(&key);
-                }
-                MPCommand::Get { key: _ } => {
-                    return Err(failure::err_msg("found get command in file"));
+                    writes.remove(key.as_slice());
+                    expiries.remove(key.as_slice());
                 }
+                _ => return Err(KvsError::UnexpectedCommand),
             }
-            offset += record_len as u64 + SIZE_OF_U64;
         }
-
-        Ok(KvStore {
-            path,
-            offset_map,
-            redundancies,
-        })
-    }
-
-    /// Compacts the KvStore file on disk
-    pub fn compact(&mut self) -> Result<()> {
-        let rand_string: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(30)
-            .map(char::from)
-            .collect();
-
-        let mut new_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&rand_string)?;
-        let mut new_offset_map: HashMap<String, u64> = HashMap::new();
-        let result: Result<()> = {
-            let mut file = OpenOptions::new().read(true).open(self.path.as_path())?;
-            let mut new_offset: u64 = 0;
-            for (_k, offset) in self.offset_map.iter() {
-                let mut buf_len: [u8; SIZE_OF_U64 as usize] = [0; SIZE_OF_U64 as usize];
-
-                file.seek(SeekFrom::Start(*offset))?;
-                file.read_exact(&mut buf_len)?;
-
-                let record_len = (&buf_len[0..SIZE_OF_U64 as usize]).read_u64::<BigEndian>()?;
-                let mut buf = vec![0u8; record_len.try_into()?];
-                file.read_exact(&mut buf)?;
-                let record: MPCommand = rmps::decode::from_read_ref(&buf)?;
-                match record {
-                    MPCommand::Set { key, .. } => {
-                        new_file.write_all(&buf_len).unwrap();
-                        new_file.write_all(&buf).unwrap();
-                        new_offset_map.insert(key, new_offset);
-                        new_offset += (buf_len.len() + buf.len()) as u64;
-                    }
-                    _ => return Err(failure::err_msg("Found invalid command at location")),
-                }
+        (&*self.db, &self.expiry).transaction(|(db, expiry)| {
+            let found = batch.removals_find_keys(
+                |key| -> std::result::Result<bool, UnabortableTransactionError> {
+                    let deadline = decode_deadline(expiry.get(key)?);
+                    Ok(db.get(key)?.is_some() && !expiry::is_expired(deadline))
+                },
+            )?;
+            if !found {
+                return abort(KvsError::KeyNotFound);
             }
-            self.offset_map = new_offset_map;
-            fs::rename(&rand_string, &self.path)?;
+            db.apply_batch(&writes)?;
+            expiry.apply_batch(&expiries)?;
             Ok(())
-        };
+        })?;
+        self.syncer.after_write()
+    }
 
-        match result {
-            Ok(()) => Ok(()),
-            Err(err) => {
-                fs::remove_file(&rand_string)?;
-                Err(failure::err_msg(err.to_string()))
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let swapped = (&*self.db, &self.expiry).transaction(|(db, expiry)| {
+            let deadline = decode_deadline(expiry.get(key.as_slice())?);
+            let current = db
+                .get(key.as_slice())?
+                .filter(|_| !expiry::is_expired(deadline));
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
             }
+            match &new {
+                Some(new) => db.insert(key.as_slice(), new.as_slice())?,
+                None => db.remove(key.as_slice())?,
+            };
+            expiry.remove(key.as_slice())?;
+            Ok(true)
+        })?;
+        if swapped {
+            self.syncer.after_write()?;
         }
+        Ok(swapped)
+    }
+
+    fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let value = (&*self.db, &self.expiry).transaction(|(db, expiry)| {
+            let deadline = decode_deadline(expiry.get(key.as_slice())?);
+            let expired = expiry::is_expired(deadline);
+            let current = db.get(key.as_slice())?.filter(|_| !expired);
+            let value = match add_to_counter(current.as_deref(), delta) {
+                Ok(value) => value,
+                Err(err) => return abort(err),
+            };
+            db.insert(key.as_slice(), value.to_string().as_bytes())?;
+            if expired {
+                expiry.remove(key.as_slice())?;
+            }
+            Ok(value)
+        })?;
+        self.syncer.after_write()?;
+        Ok(value)
     }
 }
 
-/// client to send requests to KvsServer
-pub struct KvsClient {}
+impl SledEngine {
+    /// Opens the SledEngine at the given location
+    pub fn open(path: &Path) -> Result<Self> {
+        SledEngine::open_with_durability(path, Durability::default())
+    }
 
-/// serves responses to KvsClient
-pub struct KvsServer {}
+    /// Opens the SledEngine at the given location, syncing writes according
+    /// to `durability` instead of sled's own flush schedule. With
+    /// `Durability::Never` sled's schedule is kept.
+    pub fn open_with_durability(path: &Path, durability: Durability) -> Result<Self> {
+        if !path.is_dir() {
+            return Err(KvsError::InvalidConfig(format!(
+                "{} is not a directory",
+                path.display()
+            )));
+        };
+        let lock = data_dir::claim(&RealFs, path, "sled")?;
 
-/// defines the storage interface called by KvsServer
-pub trait KvsEngine {
-    /// Set key-value pair in store
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    /// Gets a value from store
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    /// Remove key from store
-    fn remove(&mut self, key: String) -> Result<()>;
-}
+        let mut config = sled::Config::new().path(path);
+        if durability != Durability::Never {
+            // the syncer flushes instead; without one sled's own flusher is
+            // all that hands writes to the OS
+            config = config.flush_every_ms(None);
+        }
+        let db = config.open()?;
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        let syncer = {
+            let db = db.clone();
+            Syncer::new(durability, move || {
+                db.flush()?;
+                Ok(())
+            })
+        };
 
-/// KvsEngine implementation using sled crate
-pub struct SledEngine {
-    db: Db,
-}
+        sweep_expired(&db, &expiry)?;
+        let sweeper = {
+            let (db, expiry) = (db.clone(), expiry.clone());
+            Sweeper::new(EXPIRY_SWEEP_INTERVAL, move || {
+                // a sweep that fails is tried again at the next interval
+                let _ = sweep_expired(&db, &expiry);
+            })
+        };
 
-impl KvsEngine for SledEngine {
-    /// Gets a value from the key-value store
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let value = self.db.get(&key)?;
-        match value {
-            None => Ok(None),
-            Some(ivec) => {
-                let value_string = std::str::from_utf8(&ivec)?.to_owned();
-                Ok(Some(value_string))
-            }
-        }
+        Ok(SledEngine {
+            db,
+            expiry,
+            syncer: Arc::new(syncer),
+            _sweeper: Arc::new(sweeper),
+            _lock: Arc::new(lock),
+        })
     }
 
-    /// Used to set key in store
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_bytes(), value.as_bytes())?;
-        self.db.flush()?;
-        Ok(())
+    /// Deletes every key whose expiry deadline has passed, returning how many
+    /// were deleted
+    pub fn sweep_expired(&self) -> Result<usize> {
+        sweep_expired(&self.db, &self.expiry)
     }
 
-    /// Used to remove key from store
-    fn remove(&mut self, key: String) -> Result<()> {
-        let value = self.db.remove(&key)?;
-        self.db.flush()?;
-        match value {
-            None => Err(failure::err_msg("Key not found")),
-            Some(_ivec) => Ok(()),
-        }
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(expiry::is_expired(decode_deadline(self.expiry.get(key)?)))
+    }
+
+    /// Deletes `key` if it is still expired
+    fn purge(&self, key: &[u8]) -> Result<()> {
+        purge(&self.db, &self.expiry, key)?;
+        Ok(())
     }
 }
 
-impl SledEngine {
-    /// Opens the SledEngine at the given location
-    pub fn open(path: &Path) -> Result<Self> {
-        if !path.is_dir() {
-            return Err(failure::err_msg("path is not directory"));
-        };
+/// Name of the sled tree that maps keys to their expiry deadlines
+const EXPIRY_TREE: &str = "kvs_expiry";
 
-        let db = sled::open(path)?;
+/// How often a SledEngine deletes the expired keys nobody has read
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
-        Ok(SledEngine { db })
+/// Deletes `key` if it is still expired, returning whether it was
+fn purge(db: &Db, expiry: &Tree, key: &[u8]) -> Result<bool> {
+    let purged = (&**db, expiry).transaction(|(db, expiry)| {
+        if !expiry::is_expired(decode_deadline(expiry.get(key)?)) {
+            return Ok(false);
+        }
+        db.remove(key)?;
+        expiry.remove(key)?;
+        Ok(true)
+    })?;
+    Ok(purged)
+}
+
+/// Deletes every expired key, returning how many were deleted
+fn sweep_expired(db: &Db, expiry: &Tree) -> Result<usize> {
+    let mut swept = 0;
+    for entry in expiry.iter() {
+        let (key, deadline) = entry?;
+        if expiry::is_expired(decode_deadline(Some(deadline))) && purge(db, expiry, &key)? {
+            swept += 1;
+        }
     }
+    Ok(swept)
+}
+
+fn decode_deadline(deadline: Option<IVec>) -> Option<u64> {
+    deadline
+        .and_then(|ivec| ivec.as_ref().try_into().ok())
+        .map(u64::from_be_bytes)
+}
+
+fn abort<T>(err: KvsError) -> ConflictableTransactionResult<T, KvsError> {
+    Err(ConflictableTransactionError::Abort(err))
 }
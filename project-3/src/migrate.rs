@@ -0,0 +1,185 @@
+//! Upgrades a KvStore data directory written by an older kvs to the current
+//! log format
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::data_dir;
+use crate::filesystem::{Filesystem, FsFile, RealFs};
+use crate::kv_store::{
+    check_segment_header, encode_record, segment_header, segment_ids, segment_path, HINT_FILE,
+    LEGACY_LOG_FILE, SEGMENT_HEADER_LEN, SEGMENT_MAGIC,
+};
+use crate::{KvsError, MPCommand, Result};
+
+/// extension of the copy a file is rewritten into before it replaces the
+/// original
+const MIGRATE_EXTENSION: &str = "migrate";
+
+/// What `migrate` changed in a data directory
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Migration {
+    /// ids of the segments that were given a format header
+    pub upgraded_segments: Vec<u64>,
+    /// number of records carried over from a log written before segments, if
+    /// the directory held one
+    pub legacy_records: Option<u64>,
+}
+
+impl Migration {
+    /// Returns true if the directory was already in the current format
+    pub fn is_empty(&self) -> bool {
+        self.upgraded_segments.is_empty() && self.legacy_records.is_none()
+    }
+}
+
+/// Upgrades the KvStore data in `dir` to the current format
+///
+/// A single-file log from before segments becomes segment 0 and segments
+/// from before format headers are given one. Each file is rewritten under
+/// another name and renamed over the original, so running `migrate` again
+/// after a crash picks up where it left off. Segments written by a newer kvs
+/// fail the migration with `KvsError::UnsupportedFormat`.
+pub fn migrate(dir: &Path) -> Result<Migration> {
+    migrate_with_filesystem(&RealFs, dir)
+}
+
+/// Upgrades the KvStore data in `dir` on `fs` to the current format, the
+/// same way `migrate` does on the real filesystem
+pub fn migrate_with_filesystem(fs: &dyn Filesystem, dir: &Path) -> Result<Migration> {
+    if !fs.exists(dir) || fs.read_dir(dir).is_err() {
+        return Err(KvsError::InvalidConfig(format!(
+            "{} is not a directory",
+            dir.display()
+        )));
+    }
+    let _lock = data_dir::claim(fs, dir, "kvs")?;
+    remove_leftovers(fs, dir)?;
+
+    let mut migration = Migration::default();
+    let ids = segment_ids(fs, dir)?;
+    let legacy = dir.join(LEGACY_LOG_FILE);
+    // with segments around the old log was converted already, or the
+    // segments were started without it, so it is left alone
+    if ids.is_empty() && fs.exists(&legacy) {
+        migration.legacy_records = Some(convert_legacy_log(fs, dir, &legacy)?);
+        // segment 0 has to be on disk under its name before the only other
+        // copy of the data goes
+        fs.sync_dir(dir)?;
+        fs.remove_file(&legacy)?;
+    }
+    for id in ids {
+        if add_header(fs, dir, id)? {
+            migration.upgraded_segments.push(id);
+        }
+    }
+
+    if !migration.is_empty() {
+        // the hint describes the segments as they were
+        match fs.remove_file(&dir.join(HINT_FILE)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => (),
+        }
+        fs.sync_dir(dir)?;
+    }
+    Ok(migration)
+}
+
+/// Rewrites the single-file log at `legacy`, whose records are a big-endian
+/// u64 payload length followed by the payload, into segment 0. Returns the
+/// number of records carried over.
+fn convert_legacy_log(fs: &dyn Filesystem, dir: &Path, legacy: &Path) -> Result<u64> {
+    let mut reader = BufReader::new(fs.open(legacy)?);
+    let path = segment_path(dir, 0);
+    let tmp_path = migrate_path(&path);
+    let mut writer = BufWriter::new(fs.create(&tmp_path)?);
+    writer.write_all(&segment_header())?;
+
+    let mut records = 0;
+    loop {
+        let len = match reader.read_u64::<BigEndian>() {
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        let mut payload = vec![];
+        (&mut reader).take(len).read_to_end(&mut payload)?;
+        // the old kvs could leave a record cut short at the end of the log,
+        // which it never read back either
+        if (payload.len() as u64) < len {
+            break;
+        }
+        let command: MPCommand = rmp_serde::decode::from_read_ref(&payload)?;
+        match command {
+            MPCommand::Set { .. } | MPCommand::Rm { .. } => (),
+            _ => return Err(KvsError::UnexpectedCommand),
+        }
+        writer.write_all(&encode_record(&command)?)?;
+        records += 1;
+    }
+
+    replace(fs, writer, &tmp_path, &path)?;
+    Ok(records)
+}
+
+/// Prepends the format header to segment `id` if it has none. Returns true
+/// if the segment was rewritten.
+fn add_header(fs: &dyn Filesystem, dir: &Path, id: u64) -> Result<bool> {
+    let path = segment_path(dir, id);
+    let mut file = fs.open(&path)?;
+    let mut header = vec![];
+    (&mut file)
+        .take(SEGMENT_HEADER_LEN)
+        .read_to_end(&mut header)?;
+
+    // records of a headerless segment start with a length whose top bytes
+    // are zero, so they never look like the magic
+    let magic_len = header.len().min(SEGMENT_MAGIC.len());
+    if header[..magic_len] == SEGMENT_MAGIC[..magic_len] {
+        // a segment cut short in its header is cleaned up by open
+        if header.len() as u64 == SEGMENT_HEADER_LEN {
+            check_segment_header(&header, id)?;
+        }
+        return Ok(false);
+    }
+
+    let tmp_path = migrate_path(&path);
+    let mut writer = BufWriter::new(fs.create(&tmp_path)?);
+    writer.write_all(&segment_header())?;
+    writer.write_all(&header)?;
+    io::copy(&mut file, &mut writer)?;
+    replace(fs, writer, &tmp_path, &path)?;
+    Ok(true)
+}
+
+/// Makes the rewritten copy at `tmp_path` durable and moves it over `path`
+fn replace(
+    fs: &dyn Filesystem,
+    mut writer: BufWriter<Box<dyn FsFile>>,
+    tmp_path: &Path,
+    path: &Path,
+) -> Result<()> {
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs.rename(tmp_path, path)?;
+    Ok(())
+}
+
+fn migrate_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(MIGRATE_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Removes copies left behind by a migration that did not finish
+fn remove_leftovers(fs: &dyn Filesystem, dir: &Path) -> Result<()> {
+    for path in fs.read_dir(dir)? {
+        if path.extension().is_some_and(|ext| ext == MIGRATE_EXTENSION) {
+            fs.remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
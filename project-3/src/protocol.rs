@@ -0,0 +1,617 @@
+//! Framing of the requests and replies exchanged between client and server
+//!
+//! Draws inspiration from the Redis protocol. A request is a batch of
+//! commands:
+//! - `*` (start of the batch)
+//! - big-endian u64 number of commands
+//! - for every command, a big-endian u64 length followed by the
+//!   msgpack-encoded `MPCommand`
+//!
+//! The server answers every batch with exactly one reply per command, in the
+//! order the commands were sent:
+//! - `*` (start of the replies)
+//! - big-endian u64 number of replies
+//! - for every reply, either `+`, a big-endian u64 length and the value
+//!   (may be empty), `-`, a one byte `ErrorCode`, a big-endian u64 length
+//!   and the error message, or a lone `_` (nil) for a `Get` of a key that is
+//!   not set
+//!
+//! A `Ttl` command is answered with the milliseconds the key has left as a
+//! big-endian u64, or an empty value if the key never expires. A `Scan`
+//! command is answered with a msgpack-encoded `ScanPage`; its cursor is the
+//! start of the next page.
+//!
+//! Conditional writes are answered with a single byte, 1 if the write
+//! happened and 0 if its condition did not hold. An `IncrBy` command is
+//! answered with the new value of the counter as decimal text.
+//!
+//! An `MGet` command is answered with a big-endian u64 number of entries
+//! followed by one entry per key, in the order of the keys. An entry is
+//! encoded like a reply, so it is nil if the key is not set. The pairs of an
+//! `MSet` command are set all-or-nothing.
+//!
+//! The writes between a `BatchBegin` and a `BatchCommit` command of the same
+//! request are applied all-or-nothing. The markers and the writes all get the
+//! reply of the batch as a whole.
+//!
+//! A connection carries any number of batches until the client closes it, and
+//! a client may send several batches before reading any replies.
+
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::time::Duration;
+
+use rmp_serde::Serializer;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{KvsError, MPCommand, Result, WriteBatch};
+
+const SIZE_OF_U64: usize = size_of::<u64>();
+
+/// Marks a nil reply
+const NIL: u8 = b'_';
+
+/// Most pairs the server returns in one page of a scan
+pub const MAX_SCAN_PAGE: usize = 1000;
+
+/// Outcome of a single command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// the command succeeded, holding the value for a get, the time left for
+    /// a ttl and nothing otherwise
+    Ok(Vec<u8>),
+    /// the command failed with the given code and message
+    Err(ErrorCode, String),
+    /// the key of a get is not set
+    Nil,
+}
+
+impl Reply {
+    /// Turns the reply into the value or error it stands for. A nil reply
+    /// is a `KvsError::KeyNotFound`.
+    pub fn into_result(self) -> Result<Vec<u8>> {
+        match self {
+            Reply::Ok(value) => Ok(value),
+            Reply::Nil => Err(KvsError::KeyNotFound),
+            Reply::Err(ErrorCode::KeyNotFound, _) => Err(KvsError::KeyNotFound),
+            Reply::Err(ErrorCode::InvalidRequest, msg) => Err(KvsError::Protocol(msg)),
+            Reply::Err(ErrorCode::ServerError, msg) => Err(KvsError::Server(msg)),
+            Reply::Err(ErrorCode::InvalidValue, msg) => Err(KvsError::InvalidValue(msg)),
+        }
+    }
+
+    /// Turns the reply to a get into the value, or `None` if the reply is nil
+    pub fn into_value(self) -> Result<Option<Vec<u8>>> {
+        match self {
+            Reply::Nil => Ok(None),
+            reply => reply.into_result().map(Some),
+        }
+    }
+}
+
+/// Tells clients what kind of failure a `-` reply is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the key does not exist
+    KeyNotFound,
+    /// the server could not make sense of the command
+    InvalidRequest,
+    /// the server failed to carry out the command
+    ServerError,
+    /// the stored value does not suit the command, such as an increment of
+    /// a value that is not an integer
+    InvalidValue,
+}
+
+impl ErrorCode {
+    /// Picks the code to report `err` with
+    pub fn of(err: &KvsError) -> ErrorCode {
+        match err {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Protocol(_) | KvsError::Serialization(_) => ErrorCode::InvalidRequest,
+            KvsError::InvalidValue(_) => ErrorCode::InvalidValue,
+            _ => ErrorCode::ServerError,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            ErrorCode::KeyNotFound => 1,
+            ErrorCode::InvalidRequest => 2,
+            ErrorCode::ServerError => 3,
+            ErrorCode::InvalidValue => 4,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<ErrorCode> {
+        match byte {
+            1 => Ok(ErrorCode::KeyNotFound),
+            2 => Ok(ErrorCode::InvalidRequest),
+            3 => Ok(ErrorCode::ServerError),
+            4 => Ok(ErrorCode::InvalidValue),
+            _ => Err(KvsError::Protocol(format!("unknown error code {}", byte))),
+        }
+    }
+}
+
+/// Commands that are sent to the server in a single batch
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    commands: Vec<MPCommand>,
+}
+
+impl Pipeline {
+    /// Creates an empty pipeline
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    /// Queues a get of `key`
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.push(MPCommand::Get { key: key.into() })
+    }
+
+    /// Queues a set of `key` to `value`
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.push(MPCommand::Set {
+            key: key.into(),
+            value: value.into(),
+            expires_at: None,
+        })
+    }
+
+    /// Queues a set of `key` to `value` that expires after `ttl`
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> &mut Self {
+        self.push(MPCommand::SetWithTtl {
+            key: key.into(),
+            value: value.into(),
+            ttl_ms: ttl_millis(ttl),
+        })
+    }
+
+    /// Queues making `key` expire after `ttl`
+    pub fn expire(&mut self, key: impl Into<Vec<u8>>, ttl: Duration) -> &mut Self {
+        self.push(MPCommand::Expire {
+            key: key.into(),
+            ttl_ms: ttl_millis(ttl),
+        })
+    }
+
+    /// Queues a lookup of the time `key` has left
+    pub fn ttl(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.push(MPCommand::Ttl { key: key.into() })
+    }
+
+    /// Queues a scan of at most `limit` pairs from `start` up to `end`
+    pub fn scan(
+        &mut self,
+        start: impl Into<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> &mut Self {
+        self.push(MPCommand::Scan {
+            start: start.into(),
+            end,
+            limit: limit as u64,
+        })
+    }
+
+    /// Queues the writes of `batch`, to be applied all-or-nothing
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> &mut Self {
+        self.push(MPCommand::BatchBegin {
+            len: batch.len() as u64,
+        });
+        for command in batch.commands() {
+            self.push(command.clone());
+        }
+        self.push(MPCommand::BatchCommit)
+    }
+
+    /// Queues a swap of the value of `key` from `expected` to `new`
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> &mut Self {
+        self.push(MPCommand::CompareAndSwap {
+            key: key.into(),
+            expected,
+            new,
+        })
+    }
+
+    /// Queues a set of `key` to `value` if the key is absent
+    pub fn set_if_absent(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> &mut Self {
+        self.push(MPCommand::SetIfAbsent {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    /// Queues a set of `key` to `value` if the key is present
+    pub fn set_if_present(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> &mut Self {
+        self.push(MPCommand::SetIfPresent {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    /// Queues adding `delta` to the counter at `key`
+    pub fn incr_by(&mut self, key: impl Into<Vec<u8>>, delta: i64) -> &mut Self {
+        self.push(MPCommand::IncrBy {
+            key: key.into(),
+            delta,
+        })
+    }
+
+    /// Queues a get of all of `keys` as one command
+    pub fn mget<K: Into<Vec<u8>>>(&mut self, keys: impl IntoIterator<Item = K>) -> &mut Self {
+        self.push(MPCommand::MGet {
+            keys: keys.into_iter().map(Into::into).collect(),
+        })
+    }
+
+    /// Queues a set of all of `pairs` as one command, applied all-or-nothing
+    pub fn mset<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &mut self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> &mut Self {
+        self.push(MPCommand::MSet {
+            pairs: pairs
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        })
+    }
+
+    /// Queues stopping `key` from expiring
+    pub fn persist(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.push(MPCommand::Persist { key: key.into() })
+    }
+
+    /// Queues a removal of `key`
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.push(MPCommand::Rm { key: key.into() })
+    }
+
+    /// Queues an arbitrary command
+    pub fn push(&mut self, command: MPCommand) -> &mut Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Returns the queued commands
+    pub fn commands(&self) -> &[MPCommand] {
+        &self.commands
+    }
+
+    /// Returns the number of queued commands
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Returns true if no commands are queued
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Sends the queued commands over `stream` as one batch and reads their
+    /// replies, which come back in the order the commands were queued
+    pub fn send<S: Read + Write>(&self, stream: &mut S) -> Result<Vec<Reply>> {
+        write_request(stream, &self.commands)?;
+        stream.flush()?;
+        let replies = read_replies(stream)?;
+        if replies.len() != self.commands.len() {
+            return Err(KvsError::Protocol(format!(
+                "sent {} command(s) but got {} reply(s)",
+                self.commands.len(),
+                replies.len()
+            )));
+        }
+        Ok(replies)
+    }
+}
+
+/// One page of the pairs of a scan
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanPage {
+    /// key-value pairs in key order
+    pub pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// key to start the next page from, or `None` if this was the last page
+    pub cursor: Option<Vec<u8>>,
+}
+
+/// How a `ScanPage` goes over the wire, with keys and values as msgpack binary
+#[derive(Serialize, Deserialize)]
+struct WireScanPage {
+    pairs: Vec<(ByteBuf, ByteBuf)>,
+    cursor: Option<ByteBuf>,
+}
+
+impl ScanPage {
+    /// Encodes the page as the value of a `Scan` reply
+    pub fn encode(self) -> Result<Vec<u8>> {
+        let page = WireScanPage {
+            pairs: self
+                .pairs
+                .into_iter()
+                .map(|(key, value)| (ByteBuf::from(key), ByteBuf::from(value)))
+                .collect(),
+            cursor: self.cursor.map(ByteBuf::from),
+        };
+        let mut buf = vec![];
+        page.serialize(&mut Serializer::new(&mut buf))?;
+        Ok(buf)
+    }
+
+    /// Decodes the value of a `Scan` reply
+    pub fn decode(value: &[u8]) -> Result<ScanPage> {
+        let page: WireScanPage = rmp_serde::from_read_ref(value)?;
+        Ok(ScanPage {
+            pairs: page
+                .pairs
+                .into_iter()
+                .map(|(key, value)| (key.into_vec(), value.into_vec()))
+                .collect(),
+            cursor: page.cursor.map(ByteBuf::into_vec),
+        })
+    }
+}
+
+fn ttl_millis(ttl: Duration) -> u64 {
+    ttl.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Decodes the reply to an `IncrBy` command
+pub fn decode_counter(value: &[u8]) -> Result<i64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| KvsError::Protocol("bad counter reply".to_owned()))
+}
+
+/// Encodes the reply to an `MGet` command
+pub fn encode_entries(entries: &[Reply]) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    buf.write_all(&(entries.len() as u64).to_be_bytes())?;
+    for entry in entries {
+        write_reply(&mut buf, entry)?;
+    }
+    Ok(buf)
+}
+
+/// Decodes the reply to an `MGet` command
+pub fn decode_entries(mut value: &[u8]) -> Result<Vec<Reply>> {
+    let num_entries = read_u64(&mut value)?;
+    let mut entries = Vec::new();
+    for _ in 0..num_entries {
+        let marker = read_byte(&mut value)?;
+        entries.push(read_reply(&mut value, marker)?);
+    }
+    if !value.is_empty() {
+        return Err(KvsError::Protocol(format!(
+            "{} stray byte(s) after the entries",
+            value.len()
+        )));
+    }
+    Ok(entries)
+}
+
+/// Encodes the reply to a conditional write
+pub fn encode_written(written: bool) -> Vec<u8> {
+    vec![written as u8]
+}
+
+/// Decodes the reply to a conditional write
+pub fn decode_written(value: &[u8]) -> Result<bool> {
+    match value {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        _ => Err(KvsError::Protocol(format!(
+            "bad conditional write reply of {} byte(s)",
+            value.len()
+        ))),
+    }
+}
+
+/// Encodes the reply to a `Ttl` command
+pub fn encode_ttl(ttl: Option<Duration>) -> Vec<u8> {
+    ttl.map_or_else(Vec::new, |ttl| ttl_millis(ttl).to_be_bytes().to_vec())
+}
+
+/// Decodes the reply to a `Ttl` command
+pub fn decode_ttl(value: &[u8]) -> Result<Option<Duration>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let millis: [u8; SIZE_OF_U64] = value
+        .try_into()
+        .map_err(|_| KvsError::Protocol(format!("bad ttl of {} byte(s)", value.len())))?;
+    Ok(Some(Duration::from_millis(u64::from_be_bytes(millis))))
+}
+
+/// Writes a batch of commands
+pub fn write_request<W: Write>(writer: &mut W, commands: &[MPCommand]) -> Result<()> {
+    writer.write_all(b"*")?;
+    writer.write_all(&(commands.len() as u64).to_be_bytes())?;
+    for command in commands {
+        let mut serialized_command = Vec::new();
+        command.serialize(&mut Serializer::new(&mut serialized_command))?;
+        writer.write_all(&(serialized_command.len() as u64).to_be_bytes())?;
+        writer.write_all(&serialized_command)?;
+    }
+    Ok(())
+}
+
+/// Reads a batch of commands. Returns `None` if the other side closed the
+/// connection instead of starting another batch.
+pub fn read_request<R: Read>(reader: &mut R) -> Result<Option<Vec<MPCommand>>> {
+    if !read_start(reader)? {
+        return Ok(None);
+    }
+    let num_commands = read_u64(reader)?;
+    let mut commands = Vec::new();
+    for _ in 0..num_commands {
+        let ser_command = read_bytes(reader)?;
+        commands.push(rmp_serde::decode::from_read_ref(&ser_command)?);
+    }
+    Ok(Some(commands))
+}
+
+/// Reads a batch of commands like `read_request`, without blocking the
+/// thread while the rest of the batch is on its way
+pub async fn read_request_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<Vec<MPCommand>>> {
+    let mut start = [0_u8; 1];
+    if reader.read(&mut start).await? == 0 {
+        return Ok(None);
+    }
+    check_start(start[0])?;
+    let num_commands = reader.read_u64().await?;
+    let mut commands = Vec::new();
+    for _ in 0..num_commands {
+        let len = reader.read_u64().await?;
+        let mut ser_command = Vec::new();
+        (&mut *reader)
+            .take(len)
+            .read_to_end(&mut ser_command)
+            .await?;
+        check_len(&ser_command, len)?;
+        commands.push(rmp_serde::decode::from_read_ref(&ser_command)?);
+    }
+    Ok(Some(commands))
+}
+
+/// Writes the replies to a batch
+pub fn write_replies<W: Write>(writer: &mut W, replies: &[Reply]) -> Result<()> {
+    writer.write_all(b"*")?;
+    writer.write_all(&(replies.len() as u64).to_be_bytes())?;
+    for reply in replies {
+        write_reply(writer, reply)?;
+    }
+    Ok(())
+}
+
+fn write_reply<W: Write>(writer: &mut W, reply: &Reply) -> Result<()> {
+    let payload = match reply {
+        Reply::Ok(value) => {
+            writer.write_all(b"+")?;
+            &value[..]
+        }
+        Reply::Err(code, msg) => {
+            writer.write_all(b"-")?;
+            writer.write_all(&[code.to_byte()])?;
+            msg.as_bytes()
+        }
+        Reply::Nil => {
+            writer.write_all(&[NIL])?;
+            return Ok(());
+        }
+    };
+    writer.write_all(&(payload.len() as u64).to_be_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Reads the replies to a batch
+pub fn read_replies<R: Read>(reader: &mut R) -> Result<Vec<Reply>> {
+    if !read_start(reader)? {
+        return Err(KvsError::Protocol(
+            "connection closed before the reply".to_owned(),
+        ));
+    }
+    let num_values = read_u64(reader)?;
+    let mut replies = Vec::new();
+    for _ in 0..num_values {
+        let marker = read_byte(reader)?;
+        replies.push(read_reply(reader, marker)?);
+    }
+    Ok(replies)
+}
+
+/// Reads the rest of a reply that starts with `marker`
+fn read_reply<R: Read>(reader: &mut R, marker: u8) -> Result<Reply> {
+    match marker {
+        b'+' => Ok(Reply::Ok(read_bytes(reader)?)),
+        b'-' => {
+            let code = ErrorCode::from_byte(read_byte(reader)?)?;
+            let msg = String::from_utf8(read_bytes(reader)?)?;
+            Ok(Reply::Err(code, msg))
+        }
+        NIL => Ok(Reply::Nil),
+        byte => Err(KvsError::Protocol(format!(
+            "unexpected reply marker {}",
+            byte
+        ))),
+    }
+}
+
+/// Reads the `*` that starts a batch, returning false on a clean end of stream
+fn read_start<R: Read>(reader: &mut R) -> Result<bool> {
+    let mut start = [0_u8; 1];
+    loop {
+        match reader.read(&mut start) {
+            Ok(0) => return Ok(false),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    check_start(start[0])?;
+    Ok(true)
+}
+
+fn check_start(byte: u8) -> Result<()> {
+    match byte {
+        b'*' => Ok(()),
+        _ => Err(KvsError::Protocol("incorrect initial byte".to_owned())),
+    }
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut byte = [0_u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0_u8; SIZE_OF_U64];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Reads a length-prefixed byte string
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = read_u64(reader)?;
+    // don't trust the length enough to allocate it up front
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut bytes)?;
+    check_len(&bytes, len)?;
+    Ok(bytes)
+}
+
+/// Fails if the other side closed the connection before sending all `len`
+/// bytes of a byte string
+fn check_len(bytes: &[u8], len: u64) -> Result<()> {
+    if (bytes.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
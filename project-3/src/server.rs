@@ -0,0 +1,421 @@
+//! Serves KvsClient requests from a storage engine
+
+use std::io::{BufReader, BufWriter, Write};
+use std::iter;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use slog::{error, info, o, Logger};
+use tokio::io::AsyncWriteExt;
+
+use crate::protocol::{self, ErrorCode, Reply, ScanPage, MAX_SCAN_PAGE};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsEngine, KvsError, MPCommand, Result, WriteBatch};
+
+/// serves responses to KvsClient
+///
+/// Every accepted connection is handed to the thread pool, which answers
+/// batches of commands on it until the client closes the connection.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+    listener: TcpListener,
+    logger: Logger,
+    shutdown: AtomicBool,
+}
+
+/// Configures and binds a KvsServer
+pub struct KvsServerBuilder<E: KvsEngine, P: ThreadPool> {
+    engine: Option<E>,
+    pool: Option<P>,
+    addr: SocketAddr,
+    logger: Logger,
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServerBuilder<E, P> {
+    /// Sets the engine requests are served from
+    pub fn engine(mut self, engine: E) -> Self {
+        self.engine = Some(engine);
+        self
+    }
+
+    /// Sets the pool connections are handled on
+    pub fn pool(mut self, pool: P) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Sets the address to listen on, 127.0.0.1:4000 by default. Use port 0
+    /// to have the OS pick a free port and `KvsServer::local_addr` to find it.
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Sets the logger, which discards everything by default
+    pub fn logger(mut self, logger: Logger) -> Self {
+        self.logger = logger;
+        self
+    }
+
+    /// Binds the listening socket. The server does not accept connections
+    /// until `run` is called.
+    pub fn build(self) -> Result<KvsServer<E, P>> {
+        let engine = self
+            .engine
+            .ok_or_else(|| KvsError::InvalidConfig("KvsServer needs an engine".to_owned()))?;
+        let pool = self
+            .pool
+            .ok_or_else(|| KvsError::InvalidConfig("KvsServer needs a thread pool".to_owned()))?;
+        Ok(KvsServer {
+            engine,
+            pool,
+            listener: TcpListener::bind(self.addr)?,
+            logger: self.logger,
+            shutdown: AtomicBool::new(false),
+        })
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Starts configuring a server
+    pub fn builder() -> KvsServerBuilder<E, P> {
+        KvsServerBuilder {
+            engine: None,
+            pool: None,
+            addr: SocketAddr::from(([127, 0, 0, 1], 4000)),
+            logger: Logger::root(slog::Discard, o!()),
+        }
+    }
+
+    /// Returns the address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts connections until `shutdown` is called
+    pub fn run(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    // a client that reset the connection already is not
+                    // worth stopping the server for
+                    let peer_addr = match stream.peer_addr() {
+                        Ok(peer_addr) => peer_addr,
+                        Err(e) => {
+                            info!(self.logger, "Error: {}", e);
+                            continue;
+                        }
+                    };
+                    info!(
+                        self.logger,
+                        "New connection from: {peer_addr}",
+                        peer_addr = peer_addr,
+                    );
+                    let engine = self.engine.clone();
+                    let logger = self.logger.clone();
+                    self.pool.spawn(move || {
+                        if let Err(err) = handle_connection(stream, &engine, &logger) {
+                            error!(
+                                logger,
+                                "Error serving connection: {err}",
+                                err = err.to_string()
+                            );
+                        }
+                    });
+                }
+                Err(e) => {
+                    info!(self.logger, "Error: {}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `run` return. Connections that are already open are served until
+    /// their clients close them.
+    pub fn shutdown(&self) -> Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake up the accept loop so that it notices
+        TcpStream::connect(self.local_addr()?)?;
+        Ok(())
+    }
+}
+
+/// Serves clients from the engine on the current tokio runtime until the
+/// process exits, running every connection accepted by `listener` as a task.
+/// Engine calls block, so they are made from tokio's blocking pool.
+pub async fn serve_async<E: KvsEngine>(
+    engine: E,
+    listener: TcpListener,
+    logger: Logger,
+) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer_addr)) => {
+                info!(
+                    logger,
+                    "New connection from: {peer_addr}",
+                    peer_addr = peer_addr,
+                );
+                let engine = engine.clone();
+                let logger = logger.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection_async(stream, engine, &logger).await {
+                        error!(
+                            logger,
+                            "Error serving connection: {err}",
+                            err = err.to_string()
+                        );
+                    }
+                });
+            }
+            Err(e) => {
+                info!(logger, "Error: {}", e);
+            }
+        }
+    }
+}
+
+/// Handle tcp connection from client, answering batches of commands until the
+/// client closes the connection
+fn handle_connection<E: KvsEngine>(stream: TcpStream, engine: &E, logger: &Logger) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some(commands) = protocol::read_request(&mut reader)? {
+        info!(
+            logger,
+            "processing {num_commands} command(s)",
+            num_commands = commands.len()
+        );
+        let replies = execute_all(engine, &commands, logger);
+        protocol::write_replies(&mut writer, &replies)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Serves one connection without tying up a thread while the client is idle.
+/// Batches are read and answered like in `handle_connection`.
+async fn handle_connection_async<E: KvsEngine>(
+    mut stream: tokio::net::TcpStream,
+    engine: E,
+    logger: &Logger,
+) -> Result<()> {
+    while let Some(commands) = protocol::read_request_async(&mut stream).await? {
+        info!(
+            logger,
+            "processing {num_commands} command(s)",
+            num_commands = commands.len()
+        );
+        let engine = engine.clone();
+        let logger = logger.clone();
+        let replies = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+            let replies = execute_all(&engine, &commands, &logger);
+            let mut encoded = vec![];
+            protocol::write_replies(&mut encoded, &replies)?;
+            Ok(encoded)
+        })
+        .await
+        .map_err(|err| KvsError::Background(format!("blocking task failed: {}", err)))??;
+        stream.write_all(&replies).await?;
+    }
+    Ok(())
+}
+
+/// Runs the commands of a request in order, returning one reply per command.
+/// The writes between `BatchBegin` and `BatchCommit` markers are applied as
+/// one batch, and the markers and writes all get the outcome of the batch.
+fn execute_all<E: KvsEngine>(engine: &E, commands: &[MPCommand], logger: &Logger) -> Vec<Reply> {
+    let mut replies = Vec::with_capacity(commands.len());
+    let mut rest = commands;
+    while let Some((command, tail)) = rest.split_first() {
+        let len = match command {
+            MPCommand::BatchBegin { len } => {
+                usize::try_from(*len).map_or(tail.len(), |len| len.min(tail.len()))
+            }
+            command => {
+                replies.push(execute(engine, command, logger));
+                rest = tail;
+                continue;
+            }
+        };
+        let (writes, after) = tail.split_at(len);
+        let (reply, taken) = match after.first() {
+            Some(MPCommand::BatchCommit) => (write_batch(engine, writes, logger), len + 2),
+            _ => (
+                Reply::Err(
+                    ErrorCode::InvalidRequest,
+                    format!("batch of {} write(s) is not committed", len),
+                ),
+                len + 1,
+            ),
+        };
+        replies.extend(iter::repeat_n(reply, taken));
+        rest = &rest[taken..];
+    }
+    replies
+}
+
+fn write_batch<E: KvsEngine>(engine: &E, writes: &[MPCommand], logger: &Logger) -> Reply {
+    match WriteBatch::from_commands(writes).and_then(|batch| engine.write_batch(&batch)) {
+        Ok(()) => Reply::Ok(vec![]),
+        Err(err) => error_reply(err, "Error writing batch", logger),
+    }
+}
+
+/// Runs a command against the engine
+fn execute<E: KvsEngine>(engine: &E, command: &MPCommand, logger: &Logger) -> Reply {
+    let (result, context) = match command {
+        MPCommand::Get { key } => match engine.get_bytes(key) {
+            Ok(Some(value)) => return Reply::Ok(value),
+            Ok(None) => return Reply::Nil,
+            Err(err) => (err, "Error getting key"),
+        },
+        MPCommand::Set {
+            expires_at: Some(_),
+            ..
+        } => (
+            KvsError::Protocol("expiry deadlines are not accepted, send a ttl".to_owned()),
+            "Error setting key value pair",
+        ),
+        MPCommand::Set {
+            key,
+            value,
+            expires_at: None,
+        } => match engine.set_bytes(key.clone(), value.clone()) {
+            Ok(()) => return Reply::Ok(vec![]),
+            Err(err) => (err, "Error setting key value pair"),
+        },
+        MPCommand::Rm { key } => match engine.remove_bytes(key) {
+            Ok(()) => return Reply::Ok(vec![]),
+            Err(err) => (err, "Error removing key"),
+        },
+        MPCommand::SetWithTtl { key, value, ttl_ms } => {
+            let ttl = Duration::from_millis(*ttl_ms);
+            match engine.set_with_ttl(key.clone(), value.clone(), ttl) {
+                Ok(()) => return Reply::Ok(vec![]),
+                Err(err) => (err, "Error setting key value pair"),
+            }
+        }
+        MPCommand::Expire { key, ttl_ms } => {
+            match engine.expire(key, Duration::from_millis(*ttl_ms)) {
+                Ok(()) => return Reply::Ok(vec![]),
+                Err(err) => (err, "Error expiring key"),
+            }
+        }
+        MPCommand::Ttl { key } => match engine.ttl(key) {
+            Ok(ttl) => return Reply::Ok(protocol::encode_ttl(ttl)),
+            Err(err) => (err, "Error getting ttl"),
+        },
+        MPCommand::Persist { key } => match engine.persist(key) {
+            Ok(()) => return Reply::Ok(vec![]),
+            Err(err) => (err, "Error persisting key"),
+        },
+        MPCommand::Scan { start, end, limit } => match scan_page(engine, start, end, *limit) {
+            Ok(page) => return Reply::Ok(page),
+            Err(err) => (err, "Error scanning keys"),
+        },
+        MPCommand::CompareAndSwap { key, expected, new } => {
+            match engine.compare_and_swap(key.clone(), expected.clone(), new.clone()) {
+                Ok(swapped) => return Reply::Ok(protocol::encode_written(swapped)),
+                Err(err) => (err, "Error swapping value"),
+            }
+        }
+        MPCommand::SetIfAbsent { key, value } => {
+            match engine.set_if_absent(key.clone(), value.clone()) {
+                Ok(written) => return Reply::Ok(protocol::encode_written(written)),
+                Err(err) => (err, "Error setting key value pair"),
+            }
+        }
+        MPCommand::SetIfPresent { key, value } => {
+            match engine.set_if_present(key.clone(), value.clone()) {
+                Ok(written) => return Reply::Ok(protocol::encode_written(written)),
+                Err(err) => (err, "Error setting key value pair"),
+            }
+        }
+        MPCommand::IncrBy { key, delta } => match engine.incr_by(key.clone(), *delta) {
+            Ok(value) => return Reply::Ok(value.to_string().into_bytes()),
+            Err(err) => (err, "Error incrementing key"),
+        },
+        MPCommand::MGet { keys } => {
+            let entries: Vec<_> = keys
+                .iter()
+                .map(|key| match engine.get_bytes(key) {
+                    Ok(Some(value)) => Reply::Ok(value),
+                    Ok(None) => Reply::Nil,
+                    Err(err) => error_reply(err, "Error getting key", logger),
+                })
+                .collect();
+            match protocol::encode_entries(&entries) {
+                Ok(reply) => return Reply::Ok(reply),
+                Err(err) => (err, "Error getting keys"),
+            }
+        }
+        MPCommand::MSet { pairs } => {
+            let mut batch = WriteBatch::new();
+            for (key, value) in pairs {
+                batch.set(key.clone(), value.clone());
+            }
+            match engine.write_batch(&batch) {
+                Ok(()) => return Reply::Ok(vec![]),
+                Err(err) => (err, "Error setting key value pairs"),
+            }
+        }
+        MPCommand::BatchBegin { .. } | MPCommand::BatchCommit => (
+            KvsError::Protocol("batch marker outside of a batch".to_owned()),
+            "Error writing batch",
+        ),
+    };
+    error_reply(result, context, logger)
+}
+
+/// Reads one page of a scan, holding between one and `MAX_SCAN_PAGE` pairs
+fn scan_page<E: KvsEngine>(
+    engine: &E,
+    start: &[u8],
+    end: &Option<Vec<u8>>,
+    limit: u64,
+) -> Result<Vec<u8>> {
+    let limit = usize::try_from(limit).map_or(MAX_SCAN_PAGE, |limit| limit.clamp(1, MAX_SCAN_PAGE));
+    let end = match end {
+        // a range that ends where it starts or before holds nothing
+        Some(end) if end.as_slice() <= start => return ScanPage::default().encode(),
+        Some(end) => Bound::Excluded(end.clone()),
+        None => Bound::Unbounded,
+    };
+    // one pair past the page tells where the next page starts
+    let mut pairs = engine
+        .scan((Bound::Included(start.to_vec()), end), Some(limit + 1))?
+        .collect::<Result<Vec<_>>>()?;
+    let cursor = if pairs.len() > limit {
+        pairs.pop().map(|(key, _)| key)
+    } else {
+        None
+    };
+    ScanPage { pairs, cursor }.encode()
+}
+
+/// Reports a failed command. A missing key is an expected outcome, anything
+/// else is logged and sent along with what the server was doing.
+fn error_reply(err: KvsError, context: &str, logger: &Logger) -> Reply {
+    match err {
+        KvsError::KeyNotFound => Reply::Err(ErrorCode::KeyNotFound, err.to_string()),
+        err => {
+            error!(
+                logger,
+                "{context}: {err}",
+                context = context,
+                err = err.to_string()
+            );
+            Reply::Err(ErrorCode::of(&err), format!("{}: {}", context, err))
+        }
+    }
+}
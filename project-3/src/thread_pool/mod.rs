@@ -0,0 +1,26 @@
+//! Thread pools that kvs-server hands accepted connections to
+
+use crate::Result;
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+/// Runs jobs on a set of threads
+pub trait ThreadPool {
+    /// Creates a pool with the given number of threads. Pools that do not keep
+    /// a fixed set of threads are free to ignore it.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs the job on one of the pool's threads. A job that panics must not
+    /// take any capacity away from the pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
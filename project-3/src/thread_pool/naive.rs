@@ -0,0 +1,20 @@
+use std::thread;
+
+use super::ThreadPool;
+use crate::Result;
+
+/// Starts a new thread for every job
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
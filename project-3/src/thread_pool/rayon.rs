@@ -0,0 +1,25 @@
+use super::ThreadPool;
+use crate::{KvsError, Result};
+
+/// A work-stealing pool backed by rayon
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // rayon aborts the process on a panicking job unless a handler is
+            // set, and the worker itself survives the panic either way
+            .panic_handler(|_| {})
+            .build()
+            .map_err(|err| KvsError::InvalidConfig(err.to_string()))?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job)
+    }
+}
@@ -0,0 +1,64 @@
+use crossbeam_channel::{self, Receiver, Sender};
+use std::thread;
+
+use super::ThreadPool;
+use crate::{KvsError, Result};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads taking jobs off a single shared queue
+///
+/// A thread whose job panics is replaced, so the pool keeps its size.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        // with no thread to take them, jobs would have nowhere to go
+        if threads == 0 {
+            return Err(KvsError::InvalidConfig(
+                "a thread pool needs at least one thread".to_owned(),
+            ));
+        }
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+        for _ in 0..threads {
+            spawn_worker(Worker(receiver.clone()))?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("the pool has no threads left");
+    }
+}
+
+/// The receiving end of the queue owned by one thread
+struct Worker(Receiver<Job>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // the worker is only dropped while its thread is still running if a
+        // job panicked, so start a thread to take its place
+        if thread::panicking() {
+            let _ = spawn_worker(Worker(self.0.clone()));
+        }
+    }
+}
+
+fn spawn_worker(worker: Worker) -> Result<()> {
+    thread::Builder::new().spawn(move || run_worker(worker))?;
+    Ok(())
+}
+
+fn run_worker(worker: Worker) {
+    // the queue is closed once the pool is dropped
+    while let Ok(job) = worker.0.recv() {
+        job();
+    }
+}
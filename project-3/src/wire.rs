@@ -0,0 +1,51 @@
+//! Serde helpers that send lists of byte strings as msgpack binary instead of
+//! arrays of integers
+
+/// `Vec<Vec<u8>>` as a sequence of byte strings
+pub(crate) mod keys {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub(crate) fn serialize<S: Serializer>(
+        keys: &[Vec<u8>],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(keys.iter().map(|key| Bytes::new(key)))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        let keys = Vec::<ByteBuf>::deserialize(deserializer)?;
+        Ok(keys.into_iter().map(ByteBuf::into_vec).collect())
+    }
+}
+
+/// `Vec<(Vec<u8>, Vec<u8>)>` as a sequence of pairs of byte strings
+pub(crate) mod pairs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    pub(crate) fn serialize<S: Serializer>(
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            pairs
+                .iter()
+                .map(|(key, value)| (Bytes::new(key), Bytes::new(value))),
+        )
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Pairs, D::Error> {
+        let pairs = Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| (key.into_vec(), value.into_vec()))
+            .collect())
+    }
+}
@@ -1,5 +1,7 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine};
 use predicates::str::{contains, is_empty};
+use serde::Serialize;
 use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
@@ -16,6 +18,7 @@ fn client_cli_no_args() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -48,6 +51,7 @@ fn client_cli_invalid_get() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -87,6 +91,7 @@ fn client_cli_invalid_set() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -119,6 +124,7 @@ fn client_cli_invalid_rm() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -131,6 +137,7 @@ fn client_cli_invalid_subcommand() {
 
 // `kvs-client -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
@@ -142,6 +149,7 @@ fn client_cli_version() {
 
 // `kvs-server -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
@@ -152,6 +160,7 @@ fn server_cli_version() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
@@ -164,6 +173,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -172,6 +182,7 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
@@ -184,6 +195,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
         thread::sleep(Duration::from_secs(1));
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
@@ -204,6 +216,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -213,18 +226,170 @@ fn cli_wrong_engine() {
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+// A second server on the same directory should exit with a status telling
+// why it could not open it
+#[test]
+fn cli_directory_claims() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4020"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4021"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr(contains("already open"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4021"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stderr(contains("created by the kvs engine"));
+}
+
+// A pool without threads should be refused instead of crashing the server on
+// its first connection
+#[test]
+fn cli_no_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--threads",
+            "0",
+            "--addr",
+            "127.0.0.1:4023",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("at least one thread"));
+}
+
+// With `--durability none` sled should still write acknowledged sets out in
+// the background, so they survive the server being killed
+#[test]
+fn cli_sled_durability_none() {
+    let addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().unwrap();
+    let server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "sled", "--durability", "none", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut child = server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(2));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let mut child = server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+/// A command as kvs wrote it while keys and values were strings. The variants
+/// have to line up with MPCommand's.
+#[derive(Serialize)]
+#[allow(dead_code)]
+enum OldCommand {
+    Get { key: String },
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+// A directory holding the single-file log of an older kvs should be refused
+// by the server until kvs-migrate upgrades it
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut payload = vec![];
+    OldCommand::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    }
+    .serialize(&mut rmp_serde::Serializer::new(&mut payload))
+    .unwrap();
+    let mut log = (payload.len() as u64).to_be_bytes().to_vec();
+    log.extend(payload);
+    fs::write(temp_dir.path().join("my-file"), log).unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stderr(contains("kvs-migrate"));
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("(1 records)"));
+
+    // a second run has nothing left to do
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("already at format version"));
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[allow(clippy::needless_borrows_for_generic_args)]
+fn cli_access_server(engine: &str, addr: &str, server_args: &[&str]) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(server_args)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -300,12 +465,14 @@ fn cli_access_server(engine: &str, addr: &str) {
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(server_args)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -329,10 +496,450 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", "127.0.0.1:4004", &[]);
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", "127.0.0.1:4005", &[]);
+}
+
+#[test]
+fn cli_access_async_server_kvs_engine() {
+    cli_access_server("kvs", "127.0.0.1:4006", &["--async"]);
+}
+
+#[test]
+fn cli_access_async_server_sled_engine() {
+    cli_access_server("sled", "127.0.0.1:4007", &["--async"]);
+}
+
+// Binary keys and values can be passed as hex, base64 or a file
+#[test]
+fn cli_binary_values() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "ff00", "c328", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "/wA=", "--base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("wyg=\n");
+
+    let input = temp_dir.path().join("input");
+    fs::write(&input, [0u8, 1, 2, 0xff]).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "blob", "--addr", addr, "--file"])
+        .arg(&input)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let output = temp_dir.path().join("output");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "blob", "--addr", addr, "--file"])
+        .arg(&output)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(fs::read(&output).unwrap(), [0u8, 1, 2, 0xff]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "626c6f62", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("000102ff\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "zz", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client` should set and inspect the time to live of keys
+#[test]
+fn cli_key_expiry() {
+    let addr = "127.0.0.1:4014";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "session", "abc", "--ttl", "60", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "session", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("60\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["persist", "session", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "session", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["expire", "session", "0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "session", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "session", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client scan` should list keys in order
+#[test]
+fn cli_scan() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in ["user:2", "user:1", "session:1", "user:3"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, &key.to_uppercase(), "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("session:1 SESSION:1\nuser:1 USER:1\nuser:2 USER:2\nuser:3 USER:3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "user:", "--limit", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1 USER:1\nuser:2 USER:2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "user:2", "--end", "user:3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:2 USER:2\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client cas` and `setnx` should exit with status 2 when they do not write
+#[test]
+fn cli_conditional_writes() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["setnx", "job1", "worker1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["setnx", "job1", "worker2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stderr("Key already set\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "cas",
+            "job1",
+            "--expected",
+            "worker2",
+            "--new",
+            "done",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stderr("Value does not match\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "cas",
+            "job1",
+            "--expected",
+            "worker1",
+            "--new",
+            "done",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "job1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("done\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "job1", "--expected", "done", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "job1", "--new", "again", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "job1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("again\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client incr` should print the new value of the counter
+#[test]
+fn cli_incr() {
+    let addr = "127.0.0.1:4017";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "hits", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "hits", "--by", "10", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("11\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "hits", "--by", "-12", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "name", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "name", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid value"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client mset` and `mget` should handle several keys at once
+#[test]
+fn cli_mget_mset() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key1", "value1", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key1", "key3", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\nKey not found\nvalue2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key1", "key3", "--exit-status", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout("value1\nKey not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key1", "key2", "--exit-status", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\nvalue2\n");
+
+    // an odd number of arguments writes nothing
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key3", "value3", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client get --exit-status` should tell a missing key from a value that
+// reads "Key not found"
+#[test]
+fn cli_get_exit_status() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "Key not found", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--exit-status", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--exit-status", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout("Key not found\n");
+
+    // without the flag a missing key is not an error
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 }
@@ -0,0 +1,139 @@
+use kvs::protocol::{Pipeline, Reply};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result, SledEngine, WriteBatch};
+use std::sync::Arc;
+use std::thread;
+use tempfile::TempDir;
+
+fn client_against_server<E: KvsEngine>(engine: E) -> Result<()> {
+    let server = KvsServer::builder()
+        .engine(engine)
+        .pool(SharedQueueThreadPool::new(1)?)
+        .addr("127.0.0.1:0".parse()?)
+        .build()?;
+    let addr = server.local_addr()?;
+    let server = Arc::new(server);
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run())
+    };
+
+    let result = (|| -> Result<()> {
+        // every request goes over the same connection
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(client.get("key2".to_owned())?, None);
+
+        let err = client.remove("key2".to_owned()).unwrap_err();
+        assert!(matches!(err, KvsError::KeyNotFound));
+        // a failed request leaves the connection usable
+        client.remove("key1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, None);
+
+        let replies = client.pipeline(
+            Pipeline::new()
+                .set("key3".to_owned(), "value3".to_owned())
+                .get("key3".to_owned()),
+        )?;
+        assert_eq!(
+            replies,
+            vec![Reply::Ok(vec![]), Reply::Ok(b"value3".to_vec())]
+        );
+
+        client.set_bytes(&[0xff, 0x00], &[0xc3, 0x28])?;
+        assert_eq!(client.get_bytes(&[0xff, 0x00])?, Some(vec![0xc3, 0x28]));
+        client.set_bytes(b"key4", &[0xc3, 0x28])?;
+        assert!(matches!(
+            client.get("key4".to_owned()),
+            Err(KvsError::Utf8(_))
+        ));
+
+        // scans come back a page at a time
+        for i in 0..5 {
+            client.set(format!("scan{}", i), format!("value{}", i))?;
+        }
+        let page = client.scan_page(b"scan", None, 2)?;
+        assert_eq!(
+            page.pairs,
+            vec![
+                (b"scan0".to_vec(), b"value0".to_vec()),
+                (b"scan1".to_vec(), b"value1".to_vec()),
+            ]
+        );
+        assert_eq!(page.cursor, Some(b"scan2".to_vec()));
+        let page = client.scan_page(b"scan4", Some(b"scan5"), 2)?;
+        assert_eq!(page.pairs.len(), 1);
+        assert_eq!(page.cursor, None);
+        assert_eq!(client.scan_prefix(b"scan", None)?.len(), 5);
+        assert_eq!(client.scan(b"scan1", Some(b"scan4"), Some(2))?.len(), 2);
+
+        let mut batch = WriteBatch::new();
+        batch.set("batch1", "value1").remove("scan0");
+        client.write_batch(&batch)?;
+        assert_eq!(client.get("scan0".to_owned())?, None);
+        assert!(matches!(
+            client.write_batch(&batch),
+            Err(KvsError::KeyNotFound)
+        ));
+
+        assert!(client.set_if_absent(b"job1", b"worker1")?);
+        assert!(!client.set_if_absent(b"job1", b"worker2")?);
+        assert!(client.set_if_present(b"job1", b"worker3")?);
+        assert!(!client.compare_and_swap(b"job1", Some(b"worker1"), None)?);
+        assert!(client.compare_and_swap(b"job1", Some(b"worker3"), None)?);
+        assert_eq!(client.get("job1".to_owned())?, None);
+
+        client.mset(&[(b"multi1", b"value1"), (b"multi2", b"")])?;
+        assert_eq!(
+            client.mget(&[b"multi1", b"missing", b"multi2"])?,
+            vec![Some(b"value1".to_vec()), None, Some(vec![])]
+        );
+        assert_eq!(client.mget(&[])?, vec![]);
+
+        client.set("literal".to_owned(), "Key not found".to_owned())?;
+        assert_eq!(
+            client.get("literal".to_owned())?,
+            Some("Key not found".to_owned())
+        );
+        assert_eq!(client.get("missing".to_owned())?, None);
+
+        assert_eq!(client.incr(b"hits")?, 1);
+        assert_eq!(client.incr_by(b"hits", 10)?, 11);
+        assert_eq!(client.decr(b"hits")?, 10);
+        // a value that is not a counter is the caller's mistake, not the
+        // server's
+        assert!(matches!(
+            client.incr(b"key3"),
+            Err(KvsError::InvalidValue(_))
+        ));
+
+        // another client sees the writes. The pool may have a single thread,
+        // which stays busy with a connection until its client goes away.
+        drop(client);
+        let mut other = KvsClient::connect(addr)?;
+        assert_eq!(other.get("key3".to_owned())?, Some("value3".to_owned()));
+        Ok(())
+    })();
+
+    server.shutdown()?;
+    handle.join().expect("server thread panicked")?;
+    result
+}
+
+#[test]
+fn client_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    client_against_server(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn client_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    client_against_server(SledEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn client_connection_refused() {
+    assert!(KvsClient::connect("127.0.0.1:4012").is_err());
+}
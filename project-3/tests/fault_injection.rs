@@ -0,0 +1,308 @@
+use kvs::filesystem::{Fault, Filesystem, SimFs};
+use kvs::{
+    migrate_with_filesystem, Durability, KvStore, KvStoreOptions, KvsEngine, KvsError, MPCommand,
+    Result,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const KEYS: u32 = 20;
+const STEPS: u32 = 400;
+
+/// The values each key may hold: exactly one after an acknowledged write, and
+/// the old and the new one after a write that failed
+type Model = BTreeMap<String, BTreeSet<Option<String>>>;
+
+fn options(fs: &SimFs) -> KvStoreOptions {
+    KvStoreOptions {
+        max_segment_bytes: 512,
+        filesystem: Arc::new(fs.clone()),
+        ..KvStoreOptions::default()
+    }
+}
+
+/// Opens the store, retrying while injected faults get in the way
+fn reopen(fs: &SimFs) -> KvStore {
+    for _ in 0..100 {
+        if let Ok(store) = KvStore::open_with_options(Path::new("/data"), options(fs)) {
+            return store;
+        }
+    }
+    panic!("store would not open");
+}
+
+/// Checks every key against the model, then narrows the model down to what
+/// the store holds now
+fn verify(store: &KvStore, model: &mut Model, seed: u64) -> Result<()> {
+    for (key, values) in model.iter_mut() {
+        let value = store.get(key.clone())?;
+        assert!(
+            values.contains(&value),
+            "seed {}: {} holds {:?}, expected one of {:?}",
+            seed,
+            key,
+            value,
+            values
+        );
+        *values = BTreeSet::from([value]);
+    }
+    Ok(())
+}
+
+// Writes, removals and compactions hammered with short writes, EIO, ENOSPC
+// and crashes should never lose a write that was acknowledged
+#[test]
+fn acknowledged_writes_survive_faults() -> Result<()> {
+    let mut faults = 0;
+    for seed in 0..30 {
+        let fs = SimFs::new(seed);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut model: Model = (0..KEYS)
+            .map(|key_id| (format!("key{}", key_id), BTreeSet::from([None])))
+            .collect();
+        let mut store = reopen(&fs);
+        fs.inject(Fault::ShortWrite, 0.05);
+        fs.inject(Fault::Io, 0.01);
+        fs.inject(Fault::NoSpace, 0.01);
+
+        for step in 0..STEPS {
+            let key = format!("key{}", rng.gen_range(0..KEYS));
+            let values = model.get_mut(&key).unwrap();
+            let result = match rng.gen_range(0..10) {
+                0 => store.compact(),
+                1 | 2 => match store.remove(key.clone()) {
+                    Ok(()) => {
+                        *values = BTreeSet::from([None]);
+                        Ok(())
+                    }
+                    Err(KvsError::KeyNotFound) => {
+                        assert!(
+                            values.contains(&None),
+                            "seed {}: {} went missing",
+                            seed,
+                            key
+                        );
+                        Ok(())
+                    }
+                    Err(err) => {
+                        values.insert(None);
+                        Err(err)
+                    }
+                },
+                _ => {
+                    let value = format!("value{}", step);
+                    let result = store.set(key.clone(), value.clone());
+                    if result.is_ok() {
+                        values.clear();
+                    }
+                    values.insert(Some(value));
+                    result
+                }
+            };
+
+            let crash = rng.gen_bool(0.02);
+            if result.is_err() || crash {
+                if crash || rng.gen_bool(0.5) {
+                    fs.halt();
+                    drop(store);
+                    fs.crash();
+                } else {
+                    drop(store);
+                }
+                store = reopen(&fs);
+                verify(&store, &mut model, seed)?;
+            }
+        }
+
+        fs.halt();
+        drop(store);
+        fs.crash();
+        fs.clear_faults();
+        let store = reopen(&fs);
+        verify(&store, &mut model, seed)?;
+        faults += fs.faults_injected();
+    }
+    assert!(faults > 0, "no faults were injected");
+    Ok(())
+}
+
+// A write that fails part way should stop the store from writing after the
+// partial record until it is reopened
+#[test]
+fn failed_write_stops_writes() -> Result<()> {
+    let fs = SimFs::new(0);
+    let store = reopen(&fs);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fs.inject(Fault::NoSpace, 1.0);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    fs.clear_faults();
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::LogUnwritable(_))
+    ));
+    assert!(matches!(store.compact(), Err(KvsError::LogUnwritable(_))));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let store = reopen(&fs);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Data that was never synced should not survive a crash, while synced data
+// and durable directory entries should
+#[test]
+fn crash_loses_unsynced_data() -> Result<()> {
+    let fs = SimFs::new(0);
+    let dir = Path::new("/data");
+    fs.create_dir_all(dir)?;
+    let mut synced = fs.create(&dir.join("synced"))?;
+    synced.write_all(b"durable")?;
+    synced.sync_all()?;
+    fs.sync_dir(dir)?;
+    synced.set_len(0)?;
+    let mut unlisted = fs.create(&dir.join("unlisted"))?;
+    unlisted.write_all(b"lost")?;
+    unlisted.sync_all()?;
+
+    fs.crash();
+    assert_eq!(fs.read(&dir.join("synced"))?, b"durable");
+    assert!(!fs.exists(&dir.join("unlisted")));
+    assert!(synced.sync_all().is_err());
+    Ok(())
+}
+
+// A migration of a single-file log that crashes part way should never lose
+// the log's records, and running it again should finish the job
+#[test]
+fn migration_survives_crashes() -> Result<()> {
+    let mut faults = 0;
+    for seed in 0..30 {
+        let fs = SimFs::new(seed);
+        let dir = Path::new("/data");
+        fs.create_dir_all(dir)?;
+        let mut log = fs.create(&dir.join("my-file"))?;
+        for key_id in 0..KEYS {
+            let payload = rmp_serde::to_vec(&MPCommand::Set {
+                key: format!("key{}", key_id).into_bytes(),
+                value: format!("value{}", key_id).into_bytes(),
+                expires_at: None,
+            })?;
+            log.write_all(&(payload.len() as u64).to_be_bytes())?;
+            log.write_all(&payload)?;
+        }
+        log.sync_all()?;
+        fs.sync_dir(dir)?;
+        drop(log);
+
+        fs.inject(Fault::ShortWrite, 0.05);
+        fs.inject(Fault::Io, 0.05);
+        fs.inject(Fault::NoSpace, 0.05);
+        while migrate_with_filesystem(&fs, dir).is_err() {
+            fs.halt();
+            fs.crash();
+        }
+        faults += fs.faults_injected();
+        fs.clear_faults();
+        assert!(migrate_with_filesystem(&fs, dir)?.is_empty());
+
+        let store = reopen(&fs);
+        for key_id in 0..KEYS {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id)),
+                "seed {}",
+                seed
+            );
+        }
+    }
+    assert!(faults > 0, "no faults were injected");
+    Ok(())
+}
+
+/// Sets `key`, expecting the store to refuse writes after a failed sync
+fn assert_unwritable(store: &KvStore, key: &str) {
+    match store.set(key.to_owned(), "value".to_owned()) {
+        Err(KvsError::LogUnwritable(msg)) => assert!(msg.contains("sync failed"), "{}", msg),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(()) => panic!("{} was acknowledged after a failed sync", key),
+    }
+}
+
+// Once a group sync fails, no write should be acknowledged until the store
+// is reopened, however many group syncs fail after it
+#[test]
+fn failed_group_syncs_stop_writes() -> Result<()> {
+    let fs = SimFs::new(0);
+    let options = KvStoreOptions {
+        durability: Durability::GroupCommit(Duration::from_millis(5)),
+        // no rotation, whose syncs would fail the writes on their own
+        max_segment_bytes: u64::MAX,
+        ..options(&fs)
+    };
+    let store = KvStore::open_with_options(Path::new("/data"), options.clone())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    fs.inject(Fault::Sync, 1.0);
+    let writers: Vec<_> = (0..8)
+        .map(|writer| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for step in 0..4 {
+                    assert_unwritable(&store, &format!("key{}.{}", writer, step));
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert!(fs.faults_injected() > 0);
+
+    fs.clear_faults();
+    assert_unwritable(&store, "key1");
+    drop(store);
+
+    let store = KvStore::open_with_options(Path::new("/data"), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// A background sync that fails should refuse every write after it, not
+// just the next one
+#[test]
+fn failed_periodic_sync_stops_writes() -> Result<()> {
+    let fs = SimFs::new(0);
+    let options = KvStoreOptions {
+        durability: Durability::Periodic(Duration::from_millis(5)),
+        // no rotation, whose syncs would fail the writes on their own
+        max_segment_bytes: u64::MAX,
+        ..options(&fs)
+    };
+    let store = KvStore::open_with_options(Path::new("/data"), options.clone())?;
+
+    fs.inject(Fault::Sync, 1.0);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    while fs.faults_injected() == 0 {
+        thread::sleep(Duration::from_millis(5));
+    }
+    fs.clear_faults();
+    assert_unwritable(&store, "key2");
+    assert_unwritable(&store, "key3");
+    drop(store);
+
+    let store = KvStore::open_with_options(Path::new("/data"), options)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    Ok(())
+}
@@ -1,4 +1,14 @@
-use kvs::{KvStore, KvsEngine, Result, SledEngine};
+use kvs::{
+    migrate, Durability, KvStore, KvStoreOptions, KvsEngine, KvsError, MPCommand, Result,
+    SledEngine, WriteBatch, FORMAT_VERSION,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::ops::Bound;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +16,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +26,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +37,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +46,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +58,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +74,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +82,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +94,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +124,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -127,10 +137,1350 @@ fn compaction() -> Result<()> {
 
 #[test]
 fn test_sled() {
-    use std::env::current_dir;
-    let cwd = current_dir().unwrap();
-    let mut sled = SledEngine::open(&cwd).unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(temp_dir.path()).unwrap();
     sled.set("foo".to_owned(), "bar".to_owned()).unwrap();
     let foo = sled.get("foo".to_owned()).unwrap();
     assert_eq!(foo, Some("bar".to_owned()));
 }
+
+// Should spread the log over several segments and read them all back on open
+#[test]
+fn multiple_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let segment_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+            .count()
+    };
+    assert!(segment_count() > 1);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..500 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Compaction should only rewrite sealed segments that are mostly garbage
+#[test]
+fn compaction_skips_live_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    // fill a few segments with data that never gets overwritten
+    for key_id in 0..200 {
+        store.set(format!("cold{}", key_id), "value".to_owned())?;
+    }
+    let first_segment = temp_dir.path().join("0.log");
+    let first_segment_len = first_segment.metadata()?.len();
+
+    for iter in 0..50 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    store.compact()?;
+
+    assert_eq!(first_segment.metadata()?.len(), first_segment_len);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("hot".to_owned())?, Some("49".to_owned()));
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("cold{}", key_id))?,
+            Some("value".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// A record cut short at the end of the log should be truncated away on open
+#[test]
+fn torn_write_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("0.log");
+    let len = fs::metadata(&segment)?.len();
+    let file = OpenOptions::new().write(true).open(&segment)?;
+    file.set_len(len - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let torn = store.torn_write().expect("torn write should be reported");
+    assert_eq!(torn.segment, 0);
+    assert_eq!(torn.discarded_bytes, len - 3 - torn.offset);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // the log should be usable again after the truncation
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.torn_write(), None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A damaged record in the middle of the log should be reported with its offset
+#[test]
+fn corruption_in_middle_of_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("0.log");
+    let mut bytes = fs::read(&segment)?;
+    let value_pos = bytes
+        .windows(6)
+        .position(|window| window == b"value1")
+        .expect("value should be in the log");
+    bytes[value_pos] ^= 0xff;
+    fs::write(&segment, bytes)?;
+
+    let err = match KvStore::open(temp_dir.path()) {
+        Ok(_) => panic!("corruption went unnoticed"),
+        Err(err) => err,
+    };
+    match err {
+        KvsError::Corruption { segment, offset } => {
+            // the first record follows the segment header
+            assert_eq!((segment, offset), (0, 12));
+        }
+        _ => panic!("unexpected error {}", err),
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_durability() -> Result<()> {
+    assert_eq!("always".parse::<Durability>()?, Durability::Always);
+    assert_eq!("none".parse::<Durability>()?, Durability::Never);
+    assert_eq!(
+        "group:5".parse::<Durability>()?,
+        Durability::GroupCommit(Duration::from_millis(5))
+    );
+    assert_eq!(
+        "periodic:100".parse::<Durability>()?,
+        Durability::Periodic(Duration::from_millis(100))
+    );
+    assert!("periodic".parse::<Durability>().is_err());
+    assert!("sometimes".parse::<Durability>().is_err());
+    Ok(())
+}
+
+// Every durability policy should persist writes across a reopen
+#[test]
+fn durability_policies() -> Result<()> {
+    let policies = [
+        Durability::Always,
+        Durability::GroupCommit(Duration::from_millis(1)),
+        Durability::Periodic(Duration::from_millis(10)),
+        Durability::Never,
+    ];
+    for durability in policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            durability,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled = SledEngine::open_with_durability(temp_dir.path(), durability)?;
+        sled.set("key1".to_owned(), "value1".to_owned())?;
+        sled.set("key2".to_owned(), "value2".to_owned())?;
+        sled.remove("key2".to_owned())?;
+        assert_eq!(sled.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(sled.get("key2".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Reads and writes should keep working while compaction runs in the background
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 4096,
+        durability: Durability::Never,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for iter in 0..200 {
+        for key_id in 0..20 {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), format!("{}-{}", key_id, iter))?;
+            assert_eq!(store.get(key)?, Some(format!("{}-{}", key_id, iter)));
+        }
+        if iter % 7 == 0 {
+            store.remove(format!("key{}", iter % 20))?;
+            assert_eq!(store.get(format!("key{}", iter % 20))?, None);
+            store.set(
+                format!("key{}", iter % 20),
+                format!("{}-{}", iter % 20, iter),
+            )?;
+        }
+        for key_id in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}-{}", key_id, iter))
+            );
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-199", key_id))
+        );
+    }
+    Ok(())
+}
+
+fn concurrent_access<E: KvsEngine>(engine: E) -> Result<()> {
+    let writers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..250 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    engine.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(engine.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    let readers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..250 {
+                    // a key is either not written yet or holds its final value
+                    let value = engine.get(format!("key{}-{}", thread_id, i))?;
+                    assert!(value.is_none() || value == Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in writers.into_iter().chain(readers) {
+        handle.join().expect("thread panicked")?;
+    }
+
+    for thread_id in 0..4 {
+        for i in 0..250 {
+            assert_eq!(
+                engine.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Clones of an engine should see each other's writes from any thread
+#[test]
+fn concurrent_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 4096,
+        durability: Durability::Never,
+        ..KvStoreOptions::default()
+    };
+    concurrent_access(KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+#[test]
+fn concurrent_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_access(SledEngine::open_with_durability(
+        temp_dir.path(),
+        Durability::Never,
+    )?)
+}
+
+// Removing a missing key should fail with a typed error on both engines
+#[test]
+fn remove_missing_key_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(&temp_dir.path().join("kvs"))?;
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    let sled_dir = temp_dir.path().join("sled");
+    fs::create_dir(&sled_dir)?;
+    let sled = SledEngine::open(&sled_dir)?;
+    assert!(matches!(
+        sled.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+fn binary_keys_and_values<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0xc3, 0x28, 0x00, 0xa0];
+    engine.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(engine.get_bytes(&key)?, Some(value.clone()));
+    // the text shorthands refuse values that are not UTF-8
+    engine.set_bytes(b"key1".to_vec(), value)?;
+    assert!(matches!(
+        engine.get("key1".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+    engine.remove_bytes(&key)?;
+    assert_eq!(engine.get_bytes(&key)?, None);
+    assert!(matches!(
+        engine.remove_bytes(&key),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Keys and values should be allowed to hold any bytes
+#[test]
+fn binary_keys_and_values_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(KvStore::open(temp_dir.path())?)?;
+
+    // and survive a reopen
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(vec![0x80], vec![0x81])?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&[0x80])?, Some(vec![0x81]));
+    assert_eq!(store.get_bytes(&[0xff, 0x00, 0xfe])?, None);
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(SledEngine::open(temp_dir.path())?)
+}
+
+/// A command as kvs wrote it while keys and values were strings. The variants
+/// have to line up with MPCommand's.
+#[derive(Serialize)]
+#[allow(dead_code)]
+enum OldCommand {
+    Get { key: String },
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+// Logs written while keys and values were strings should open once migrated
+#[test]
+fn string_records_still_decode() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut payload = vec![];
+    OldCommand::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    }
+    .serialize(&mut rmp_serde::Serializer::new(&mut payload))?;
+    let mut record = (payload.len() as u64).to_be_bytes().to_vec();
+    record.extend(crc32fast::hash(&payload).to_be_bytes());
+    record.extend(payload);
+    fs::write(temp_dir.path().join("0.log"), record)?;
+
+    // the segment predates format headers
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat(msg)) => assert!(msg.contains("kvs-migrate")),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("headerless segment was opened"),
+    }
+    let migration = migrate(temp_dir.path())?;
+    assert_eq!(migration.upgraded_segments, vec![0]);
+    assert_eq!(migration.legacy_records, None);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert!(migrate(temp_dir.path())?.is_empty());
+    Ok(())
+}
+
+// The single file written before segments, records without checksums, should
+// become the first segment
+#[test]
+fn legacy_log_migration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = vec![];
+    for command in [
+        OldCommand::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        OldCommand::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        OldCommand::Rm {
+            key: "key1".to_owned(),
+        },
+    ] {
+        let mut payload = vec![];
+        command.serialize(&mut rmp_serde::Serializer::new(&mut payload))?;
+        log.extend((payload.len() as u64).to_be_bytes());
+        log.extend(payload);
+    }
+    // a record the old kvs never finished writing
+    log.extend(100u64.to_be_bytes());
+    log.extend(b"partial");
+    fs::write(temp_dir.path().join("my-file"), log)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat(msg)) => assert!(msg.contains("kvs-migrate")),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("single-file log was opened"),
+    }
+    // the old log counts as kvs data
+    assert!(matches!(
+        SledEngine::open(temp_dir.path()),
+        Err(KvsError::WrongEngine { .. })
+    ));
+
+    let migration = migrate(temp_dir.path())?;
+    assert_eq!(migration.legacy_records, Some(3));
+    assert!(!temp_dir.path().join("my-file").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.torn_write(), None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Segments written by a newer kvs should be refused rather than misread
+#[test]
+fn segment_header_validation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("0.log");
+    let original = fs::read(&segment)?;
+    assert_eq!(&original[..4], b"KVSL");
+    assert_eq!(original[4..8], FORMAT_VERSION.to_be_bytes());
+
+    let newer_version = {
+        let mut bytes = original.clone();
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        bytes
+    };
+    let unknown_flag = {
+        let mut bytes = original.clone();
+        bytes[11] |= 0x80;
+        bytes
+    };
+    for (bytes, expected) in [(newer_version, "format version"), (unknown_flag, "0x80")] {
+        fs::write(&segment, bytes)?;
+        for result in [
+            KvStore::open(temp_dir.path()).map(drop),
+            migrate(temp_dir.path()).map(drop),
+        ] {
+            match result {
+                Err(KvsError::UnsupportedFormat(msg)) => assert!(msg.contains(expected), "{}", msg),
+                Err(err) => panic!("unexpected error {}", err),
+                Ok(()) => panic!("unsupported segment was accepted"),
+            }
+        }
+    }
+
+    fs::write(&segment, original)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+fn key_expiry<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set_with_ttl(
+        b"key1".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    assert_eq!(engine.get_bytes(b"key1")?, Some(b"value1".to_vec()));
+    let ttl = engine.ttl(b"key1")?.expect("key1 should expire");
+    assert!(ttl <= Duration::from_millis(200));
+
+    // a plain set clears the expiry, persist does too
+    engine.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_bytes(b"key2".to_vec(), b"value2".to_vec())?;
+    assert_eq!(engine.ttl(b"key2")?, None);
+    engine.set_with_ttl(
+        b"key3".to_vec(),
+        b"value3".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    engine.persist(b"key3")?;
+    assert_eq!(engine.ttl(b"key3")?, None);
+
+    engine.expire(b"key2", Duration::from_millis(200))?;
+    assert!(engine.ttl(b"key2")?.is_some());
+    assert!(matches!(
+        engine.expire(b"key4", Duration::from_secs(1)),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    thread::sleep(Duration::from_millis(300));
+    for key in [&b"key1"[..], b"key2"] {
+        assert_eq!(engine.get_bytes(key)?, None);
+        assert!(matches!(engine.ttl(key), Err(KvsError::KeyNotFound)));
+        assert!(matches!(engine.persist(key), Err(KvsError::KeyNotFound)));
+        assert!(matches!(
+            engine.remove_bytes(key),
+            Err(KvsError::KeyNotFound)
+        ));
+    }
+    assert_eq!(engine.get_bytes(b"key3")?, Some(b"value3".to_vec()));
+
+    // an expired key can be set again
+    engine.set_bytes(b"key1".to_vec(), b"value4".to_vec())?;
+    assert_eq!(engine.get_bytes(b"key1")?, Some(b"value4".to_vec()));
+    Ok(())
+}
+
+// Keys given a time to live should disappear once it has passed
+#[test]
+fn key_expiry_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    key_expiry(KvStore::open(temp_dir.path())?)?;
+
+    // expiry survives a reopen
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"key2")?, None);
+    assert_eq!(store.ttl(b"key3")?, None);
+    store.set_with_ttl(
+        b"key5".to_vec(),
+        b"value5".to_vec(),
+        Duration::from_secs(60),
+    )?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.ttl(b"key5")?.is_some());
+    Ok(())
+}
+
+#[test]
+fn key_expiry_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    key_expiry(SledEngine::open(temp_dir.path())?)?;
+
+    let sled = reopen_sled(temp_dir.path())?;
+    sled.set_with_ttl(
+        b"key5".to_vec(),
+        b"value5".to_vec(),
+        Duration::from_secs(60),
+    )?;
+    drop(sled);
+    let sled = reopen_sled(temp_dir.path())?;
+    assert!(sled.ttl(b"key5")?.is_some());
+    Ok(())
+}
+
+// Sled should delete expired keys that are never read again, on open and in
+// the background
+#[test]
+fn expired_keys_swept_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(temp_dir.path())?;
+    let short = Duration::from_millis(50);
+    sled.set_with_ttl(b"key1".to_vec(), b"value1".to_vec(), short)?;
+    sled.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        Duration::from_secs(60),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(sled.sweep_expired()?, 1);
+    assert_eq!(sled.sweep_expired()?, 0);
+
+    sled.set_with_ttl(b"key3".to_vec(), b"value3".to_vec(), short)?;
+    drop(sled);
+    thread::sleep(Duration::from_millis(100));
+    let sled = reopen_sled(temp_dir.path())?;
+    assert_eq!(sled.sweep_expired()?, 0);
+
+    sled.set_with_ttl(b"key4".to_vec(), b"value4".to_vec(), short)?;
+    thread::sleep(Duration::from_millis(2500));
+    assert_eq!(sled.sweep_expired()?, 0);
+    assert_eq!(sled.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Opens a sled database that was just closed. Sled's own threads may hold on
+/// to its file lock for a moment after the last handle is dropped.
+fn reopen_sled(path: &Path) -> Result<SledEngine> {
+    for _ in 0..50 {
+        if let Ok(sled) = SledEngine::open(path) {
+            return Ok(sled);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    SledEngine::open(path)
+}
+
+// Compaction should drop expired values without bringing back older ones
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    // the first segment is all live data, so compaction leaves it alone
+    store.set("session0".to_owned(), "old".to_owned())?;
+    for i in 0..5 {
+        store.set(format!("live{}", i), "x".repeat(50))?;
+    }
+    store.compact()?;
+
+    store.set_with_ttl(
+        b"session0".to_vec(),
+        b"expired-value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    for i in 1..40 {
+        store.set_with_ttl(
+            format!("session{}", i).into_bytes(),
+            b"abc".to_vec(),
+            Duration::from_millis(100),
+        )?;
+    }
+
+    thread::sleep(Duration::from_millis(200));
+    store.compact()?;
+    assert_eq!(store.get("session0".to_owned())?, None);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let contents = fs::read(entry?.path())?;
+        assert!(!contents
+            .windows(b"expired-value".len())
+            .any(|window| window == b"expired-value"));
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("session0".to_owned())?, None);
+    for i in 0..5 {
+        assert_eq!(store.get(format!("live{}", i))?, Some("x".repeat(50)));
+    }
+    Ok(())
+}
+
+fn pairs(scan: kvs::KvPairs) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    scan.collect()
+}
+
+fn keys(scan: kvs::KvPairs) -> Result<Vec<Vec<u8>>> {
+    Ok(pairs(scan)?.into_iter().map(|(key, _)| key).collect())
+}
+
+fn ordered_scans<E: KvsEngine>(engine: E) -> Result<()> {
+    for key in [
+        &b"b"[..],
+        b"ab",
+        b"a",
+        b"abc",
+        b"c",
+        b"\xff",
+        b"\xff\xff",
+        b"d",
+    ] {
+        engine.set_bytes(key.to_vec(), key.to_ascii_uppercase())?;
+    }
+    engine.remove_bytes(b"d")?;
+    engine.set_with_ttl(b"ac".to_vec(), b"AC".to_vec(), Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(100));
+
+    assert_eq!(
+        keys(engine.scan(.., None)?)?,
+        vec![
+            b"a".to_vec(),
+            b"ab".to_vec(),
+            b"abc".to_vec(),
+            b"b".to_vec(),
+            b"c".to_vec(),
+            b"\xff".to_vec(),
+            b"\xff\xff".to_vec(),
+        ]
+    );
+    assert_eq!(
+        pairs(engine.scan(b"ab".to_vec()..b"b".to_vec(), None)?)?,
+        vec![
+            (b"ab".to_vec(), b"AB".to_vec()),
+            (b"abc".to_vec(), b"ABC".to_vec()),
+        ]
+    );
+    assert_eq!(
+        keys(engine.scan(b"abc".to_vec()..=b"c".to_vec(), Some(2))?)?,
+        vec![b"abc".to_vec(), b"b".to_vec()]
+    );
+    assert_eq!(
+        keys(engine.scan_prefix(b"ab")?)?,
+        vec![b"ab".to_vec(), b"abc".to_vec()]
+    );
+    assert_eq!(
+        keys(engine.scan_prefix(b"\xff")?)?,
+        vec![b"\xff".to_vec(), b"\xff\xff".to_vec()]
+    );
+    assert_eq!(keys(engine.scan_prefix(b"z")?)?, Vec::<Vec<u8>>::new());
+
+    // a range that ends before it starts holds nothing
+    assert_eq!(
+        keys(engine.scan(b"b".to_vec()..b"a".to_vec(), None)?)?,
+        Vec::<Vec<u8>>::new()
+    );
+    assert_eq!(
+        keys(engine.scan(b"b".to_vec()..=b"a".to_vec(), None)?)?,
+        Vec::<Vec<u8>>::new()
+    );
+    let same = (
+        Bound::Excluded(b"b".to_vec()),
+        Bound::Excluded(b"b".to_vec()),
+    );
+    assert_eq!(keys(engine.scan(same, None)?)?, Vec::<Vec<u8>>::new());
+    Ok(())
+}
+
+// Scans should list live keys in order
+#[test]
+fn ordered_scans_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ordered_scans(KvStore::open(temp_dir.path())?)?;
+
+    // the order is rebuilt on open
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(keys(store.scan(..b"b".to_vec(), None)?)?.len(), 3);
+    Ok(())
+}
+
+#[test]
+fn ordered_scans_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ordered_scans(SledEngine::open(temp_dir.path())?)
+}
+
+// A long scan should keep going while compaction moves its records
+#[test]
+fn scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for round in 0..3 {
+        for i in 0..200 {
+            store.set(format!("key{:03}", i), format!("value{}", round))?;
+        }
+    }
+
+    let mut scan = store.scan(.., None)?;
+    let first = scan.next().expect("the store is not empty")?;
+    assert_eq!(first, (b"key000".to_vec(), b"value2".to_vec()));
+    store.compact()?;
+    let rest = pairs(scan)?;
+    assert_eq!(rest.len(), 199);
+    assert!(rest.iter().all(|(_, value)| value == b"value2"));
+    Ok(())
+}
+
+fn write_batches<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set_bytes(b"key1".to_vec(), b"value1".to_vec())?;
+    engine.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        Duration::from_secs(60),
+    )?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value3")
+        .set("key3", "value3")
+        .remove("key1")
+        .set("key4", "value4")
+        .remove("key4");
+    engine.write_batch(&batch)?;
+    assert_eq!(engine.get_bytes(b"key1")?, None);
+    assert_eq!(engine.get_bytes(b"key2")?, Some(b"value3".to_vec()));
+    assert_eq!(engine.ttl(b"key2")?, None);
+    assert_eq!(engine.get_bytes(b"key3")?, Some(b"value3".to_vec()));
+    assert_eq!(engine.get_bytes(b"key4")?, None);
+
+    // a removal of a missing key fails the batch before anything is written
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value5").remove("key3").remove("key3");
+    assert!(matches!(
+        engine.write_batch(&batch),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get_bytes(b"key3")?, Some(b"value3".to_vec()));
+
+    engine.write_batch(&WriteBatch::new())?;
+    Ok(())
+}
+
+// Batches should apply all of their writes or none of them
+#[test]
+fn write_batches_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batches(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.torn_write(), None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn write_batches_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batches(SledEngine::open(temp_dir.path())?)
+}
+
+// A batch cut short by a crash should be left out entirely on open
+#[test]
+fn uncommitted_batch_recovery() -> Result<()> {
+    let mut commit = vec![];
+    MPCommand::BatchCommit.serialize(&mut rmp_serde::Serializer::new(&mut commit))?;
+    // length and checksum come first
+    let commit_len = 12 + commit.len() as u64;
+
+    // both with the commit missing entirely and with it half written
+    for cut in [commit_len, 1] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let before = fs::metadata(temp_dir.path().join("0.log"))?.len();
+        let mut batch = WriteBatch::new();
+        batch.set("key2", "value2").remove("key1");
+        store.write_batch(&batch)?;
+        drop(store);
+
+        let segment = temp_dir.path().join("0.log");
+        let len = fs::metadata(&segment)?.len();
+        let file = OpenOptions::new().write(true).open(&segment)?;
+        file.set_len(len - cut)?;
+        drop(file);
+
+        let store = KvStore::open(temp_dir.path())?;
+        let torn = store.torn_write().expect("torn batch should be reported");
+        assert_eq!(torn.offset, before);
+        assert_eq!(torn.discarded_bytes, len - cut - before);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.torn_write(), None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+fn conditional_writes<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = || b"key1".to_vec();
+    let value = |value: &str| Some(value.as_bytes().to_vec());
+
+    assert!(!engine.compare_and_swap(key(), value("value1"), value("value2"))?);
+    assert!(engine.compare_and_swap(key(), None, value("value1"))?);
+    assert!(!engine.compare_and_swap(key(), None, value("value2"))?);
+    assert!(engine.compare_and_swap(key(), value("value1"), value("value2"))?);
+    assert_eq!(engine.get_bytes(b"key1")?, value("value2"));
+    assert!(engine.compare_and_swap(key(), value("value2"), None)?);
+    assert_eq!(engine.get_bytes(b"key1")?, None);
+    assert!(engine.compare_and_swap(key(), None, None)?);
+
+    assert!(!engine.set_if_present(key(), b"value3".to_vec())?);
+    assert!(engine.set_if_absent(key(), b"value3".to_vec())?);
+    assert!(!engine.set_if_absent(key(), b"value4".to_vec())?);
+    assert!(engine.set_if_present(key(), b"value4".to_vec())?);
+    assert_eq!(engine.get_bytes(b"key1")?, value("value4"));
+
+    // an expired key counts as absent, and a swapped in value does not expire
+    engine.set_with_ttl(
+        b"key2".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(!engine.set_if_present(b"key2".to_vec(), b"value2".to_vec())?);
+    assert!(engine.set_if_absent(b"key2".to_vec(), b"value2".to_vec())?);
+    assert_eq!(engine.ttl(b"key2")?, None);
+    Ok(())
+}
+
+// Conditional writes should only happen when their condition holds
+#[test]
+fn conditional_writes_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    conditional_writes(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn conditional_writes_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    conditional_writes(SledEngine::open(temp_dir.path())?)
+}
+
+fn racing_claims<E: KvsEngine>(engine: E) -> Result<()> {
+    let claims: Vec<Vec<Vec<u8>>> = (0..4)
+        .map(|worker| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<Vec<Vec<u8>>> {
+                let mut claimed = vec![];
+                for job in 0..50 {
+                    let key = format!("job{}", job).into_bytes();
+                    if engine.set_if_absent(key.clone(), vec![worker])? {
+                        claimed.push(key);
+                    }
+                }
+                Ok(claimed)
+            })
+        })
+        .map(|handle| handle.join().expect("worker panicked"))
+        .collect::<Result<_>>()?;
+
+    // every job is claimed exactly once, by the worker whose value stuck
+    let mut all: Vec<_> = claims.concat();
+    all.sort();
+    all.dedup();
+    assert_eq!(all.len(), 50);
+    assert_eq!(claims.iter().map(Vec::len).sum::<usize>(), 50);
+    for (worker, keys) in claims.iter().enumerate() {
+        for key in keys {
+            assert_eq!(engine.get_bytes(key)?, Some(vec![worker as u8]));
+        }
+    }
+    Ok(())
+}
+
+// Workers racing to claim the same keys should each win a disjoint share
+#[test]
+fn racing_claims_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    racing_claims(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn racing_claims_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    racing_claims(SledEngine::open(temp_dir.path())?)
+}
+
+fn counters<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.incr(b"hits".to_vec())?, 1);
+    assert_eq!(engine.incr_by(b"hits".to_vec(), 41)?, 42);
+    assert_eq!(engine.decr(b"hits".to_vec())?, 41);
+    assert_eq!(engine.get_bytes(b"hits")?, Some(b"41".to_vec()));
+    assert_eq!(engine.decr(b"balance".to_vec())?, -1);
+
+    engine.set_bytes(b"name".to_vec(), b"value1".to_vec())?;
+    assert!(matches!(
+        engine.incr(b"name".to_vec()),
+        Err(KvsError::InvalidValue(_))
+    ));
+    engine.set_bytes(b"big".to_vec(), i64::MAX.to_string().into_bytes())?;
+    assert!(matches!(
+        engine.incr(b"big".to_vec()),
+        Err(KvsError::InvalidValue(_))
+    ));
+    assert_eq!(
+        engine.get_bytes(b"big")?,
+        Some(i64::MAX.to_string().into_bytes())
+    );
+
+    // a counter keeps its expiry, and starts over once it has expired
+    engine.set_with_ttl(b"rate".to_vec(), b"5".to_vec(), Duration::from_secs(60))?;
+    assert_eq!(engine.incr(b"rate".to_vec())?, 6);
+    assert!(engine.ttl(b"rate")?.is_some());
+    engine.expire(b"rate", Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(engine.incr(b"rate".to_vec())?, 1);
+    assert_eq!(engine.ttl(b"rate")?, None);
+    Ok(())
+}
+
+// Counters should start at zero and reject values that are not integers
+#[test]
+fn counters_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    counters(KvStore::open(temp_dir.path())?)?;
+
+    // counters survive a reopen
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr(b"hits".to_vec())?, 42);
+    Ok(())
+}
+
+#[test]
+fn counters_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    counters(SledEngine::open(temp_dir.path())?)
+}
+
+fn concurrent_increments<E: KvsEngine>(engine: E) -> Result<()> {
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    engine.incr(b"counter".to_vec())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("worker panicked")?;
+    }
+    assert_eq!(engine.get_bytes(b"counter")?, Some(b"800".to_vec()));
+    Ok(())
+}
+
+// Increments racing on the same key should not lose updates
+#[test]
+fn concurrent_increments_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_increments(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn concurrent_increments_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_increments(SledEngine::open(temp_dir.path())?)
+}
+
+// Open should start from the hint written by compaction and only replay the
+// segments written after it
+#[test]
+fn hint_file_startup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("cold{}", key_id), "value".to_owned())?;
+    }
+    for iter in 0..50 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    store.compact()?;
+    let hint = temp_dir.path().join("index.hint");
+    assert!(hint.exists());
+
+    // writes after the compaction only live in the tail
+    store.set("cold1".to_owned(), "changed".to_owned())?;
+    store.remove("cold2".to_owned())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("hot".to_owned())?, Some("49".to_owned()));
+        assert_eq!(store.get("cold1".to_owned())?, Some("changed".to_owned()));
+        assert_eq!(store.get("cold2".to_owned())?, None);
+        assert_eq!(store.get("new".to_owned())?, Some("value".to_owned()));
+        for key_id in 3..200 {
+            assert_eq!(
+                store.get(format!("cold{}", key_id))?,
+                Some("value".to_owned())
+            );
+        }
+        Ok(())
+    };
+    check(&KvStore::open_with_options(
+        temp_dir.path(),
+        options.clone(),
+    )?)?;
+
+    // damage the first record of a segment the hint covers: open never reads
+    // it, but a full scan of the log refuses it
+    let segment = temp_dir.path().join("0.log");
+    let mut contents = fs::read(&segment)?;
+    contents[20] ^= 0xff;
+    fs::write(&segment, &contents)?;
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("cold3".to_owned())?, Some("value".to_owned()));
+    assert!(store.get("cold0".to_owned()).is_err());
+    drop(store);
+
+    fs::rename(&hint, temp_dir.path().join("saved.hint"))?;
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options.clone()),
+        Err(KvsError::Corruption { segment: 0, .. })
+    ));
+    contents[20] ^= 0xff;
+    fs::write(&segment, &contents)?;
+    fs::rename(temp_dir.path().join("saved.hint"), &hint)?;
+
+    // a hint that fails its checksum is ignored
+    let mut damaged = fs::read(&hint)?;
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 0xff;
+    fs::write(&hint, &damaged)?;
+    check(&KvStore::open_with_options(
+        temp_dir.path(),
+        options.clone(),
+    )?)?;
+    Ok(())
+}
+
+// A hint left behind by an earlier compaction should not be trusted once the
+// segments it covers have changed
+#[test]
+fn stale_hint_is_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..50 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.compact()?;
+    let hint = temp_dir.path().join("index.hint");
+    let old_hint = fs::read(&hint)?;
+
+    for iter in 50..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+        store.set(format!("key{}", iter), "value".to_owned())?;
+    }
+    store.compact()?;
+    drop(store);
+
+    // as if the second compaction died before writing its hint
+    fs::write(&hint, old_hint)?;
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+    for iter in 50..100 {
+        assert_eq!(store.get(format!("key{}", iter))?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// A directory should only be open in one store at a time
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+    assert!(matches!(
+        SledEngine::open(temp_dir.path()),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+
+    // clones share the claim on the directory
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// A directory should only open with the engine that created it
+#[test]
+fn engine_marker() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(kvs_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        SledEngine::open(kvs_dir.path()),
+        Err(KvsError::WrongEngine { requested, found }) if requested == "sled" && found == "kvs"
+    ));
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    SledEngine::open(sled_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        KvStore::open(sled_dir.path()),
+        Err(KvsError::WrongEngine { requested, found }) if requested == "kvs" && found == "sled"
+    ));
+
+    // directories from before the marker are recognized by their files
+    fs::remove_file(kvs_dir.path().join("kvs.engine"))?;
+    assert!(matches!(
+        SledEngine::open(kvs_dir.path()),
+        Err(KvsError::WrongEngine { .. })
+    ));
+    let store = KvStore::open(kvs_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a log file that is not a segment leaves a directory free for any engine
+    let log_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(log_dir.path().join("server.log"), "listening\n")?;
+    SledEngine::open(log_dir.path())?;
+    Ok(())
+}
+
+/// Reads every file of a data directory by name
+fn dir_files(dir: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        files.insert(name, fs::read(&path)?);
+    }
+    Ok(files)
+}
+
+// Whatever step a compaction dies at, open should come back with the same
+// data and without the files the compaction left behind
+#[test]
+fn compaction_crash_recovery() -> Result<()> {
+    let source = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_segment_bytes: 1024,
+        ..KvStoreOptions::default()
+    };
+    let mut expected = BTreeMap::new();
+    let store = KvStore::open_with_options(source.path(), options.clone())?;
+    for round in 0..4 {
+        for key_id in 0..100 {
+            if round > 0 && key_id % 3 != 0 {
+                continue;
+            }
+            let (key, value) = (format!("key{}", key_id), format!("value{}", round));
+            store.set(key.clone(), value.clone())?;
+            expected.insert(key, value);
+        }
+    }
+    for key_id in (1..100).step_by(10) {
+        let key = format!("key{}", key_id);
+        store.remove(key.clone())?;
+        expected.remove(&key);
+    }
+    drop(store);
+    let before = dir_files(source.path())?;
+
+    let store = KvStore::open_with_options(source.path(), options.clone())?;
+    store.compact()?;
+    drop(store);
+    let after = dir_files(source.path())?;
+
+    let segments = |files: &BTreeMap<String, Vec<u8>>| -> BTreeSet<String> {
+        files
+            .keys()
+            .filter(|name| name.ends_with(".log"))
+            .cloned()
+            .collect()
+    };
+    let (before_segments, after_segments) = (segments(&before), segments(&after));
+    let candidates: Vec<&String> = before_segments.difference(&after_segments).collect();
+    let mut started: Vec<&String> = after_segments.difference(&before_segments).collect();
+    assert!(!candidates.is_empty());
+    // the output and the new active segment, in that order
+    started.sort_by_key(|name| name.trim_end_matches(".log").parse::<u64>().unwrap());
+    assert_eq!(started.len(), 2);
+    let active = started.pop().unwrap();
+    let output = started.pop().unwrap();
+    let output_tmp = output.replace(".log", ".compact");
+
+    let mut states: Vec<BTreeMap<String, Vec<u8>>> = vec![];
+    // died while writing the output
+    let mut state = before.clone();
+    state.insert(active.clone(), after[active].clone());
+    let half_written = after[output][..after[output].len() / 2].to_vec();
+    state.insert(output_tmp.clone(), half_written);
+    states.push(state.clone());
+    // died after syncing the output, before renaming it
+    state.insert(output_tmp, after[output].clone());
+    states.push(state);
+    // died after the rename, before or while removing the old segments
+    for removed in 0..candidates.len() {
+        let mut state = after.clone();
+        state.remove("index.hint");
+        for candidate in &candidates[removed..] {
+            state.insert((*candidate).clone(), before[*candidate].clone());
+        }
+        states.push(state);
+    }
+    // died while writing the hint
+    let mut state = after.clone();
+    let hint = state
+        .remove("index.hint")
+        .expect("compaction should write a hint");
+    state.insert("index.hint.tmp".to_owned(), hint[..hint.len() / 2].to_vec());
+    states.push(state);
+
+    for state in states {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        for (name, contents) in &state {
+            fs::write(temp_dir.path().join(name), contents)?;
+        }
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.torn_write(), None);
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key.clone())?, expected.get(&key).cloned());
+        }
+        let leftovers: Vec<String> = dir_files(temp_dir.path())?
+            .into_keys()
+            .filter(|name| name.ends_with(".compact") || name.ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?} left behind", leftovers);
+
+        // the store should compact cleanly from there
+        store.compact()?;
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsError, Result, SledEngine};
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// The in-memory store from project 1, which every engine should agree with
+#[path = "../../project-1/src/lib.rs"]
+mod reference;
+
+const KEYS: u8 = 8;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(String, String),
+    Get(String),
+    Remove(String),
+    Reopen,
+    Compact,
+}
+
+/// Operations on a handful of keys, so that they keep running into each other
+fn op() -> impl Strategy<Value = Op> {
+    let key = (0..KEYS).prop_map(|key_id| format!("key{}", key_id));
+    prop_oneof![
+        4 => (key.clone(), "[a-z0-9]{0,40}").prop_map(|(key, value)| Op::Set(key, value)),
+        3 => key.clone().prop_map(Op::Get),
+        2 => key.prop_map(Op::Remove),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+    ]
+}
+
+/// What the model needs from an engine beyond `KvsEngine`
+trait Engine: KvsEngine + Sized {
+    fn open(path: &Path) -> Result<Self>;
+    fn compact(&self) -> Result<()>;
+}
+
+impl Engine for KvStore {
+    fn open(path: &Path) -> Result<Self> {
+        // small segments, so that rotation and background compaction kick in
+        // within a few dozen writes
+        let options = KvStoreOptions {
+            max_segment_bytes: 256,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with_options(path, options)
+    }
+
+    fn compact(&self) -> Result<()> {
+        KvStore::compact(self)
+    }
+}
+
+impl Engine for SledEngine {
+    fn open(path: &Path) -> Result<Self> {
+        // sled's own threads may hold on to its file lock for a moment after
+        // the last handle is dropped
+        for _ in 0..50 {
+            if let Ok(sled) = SledEngine::open(path) {
+                return Ok(sled);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        SledEngine::open(path)
+    }
+
+    fn compact(&self) -> Result<()> {
+        // sled reclaims space on its own
+        Ok(())
+    }
+}
+
+fn fail(err: KvsError) -> TestCaseError {
+    TestCaseError::fail(err.to_string())
+}
+
+/// Runs `ops` against a fresh engine and the reference store side by side,
+/// then reopens the engine and compares every key
+fn check_against_reference<E: Engine>(ops: &[Op]) -> std::result::Result<(), TestCaseError> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = E::open(temp_dir.path()).map_err(fail)?;
+    let mut reference = reference::KvStore::new();
+
+    for op in ops {
+        match op {
+            Op::Set(key, value) => {
+                engine.set(key.clone(), value.clone()).map_err(fail)?;
+                reference.set(key.clone(), value.clone());
+            }
+            Op::Get(key) => {
+                prop_assert_eq!(
+                    engine.get(key.clone()).map_err(fail)?,
+                    reference.get(key.clone())
+                );
+            }
+            Op::Remove(key) => {
+                let existed = reference.get(key.clone()).is_some();
+                match engine.remove(key.clone()) {
+                    Ok(()) => prop_assert!(existed, "removed missing key {}", key),
+                    Err(KvsError::KeyNotFound) => {
+                        prop_assert!(!existed, "{} not found", key)
+                    }
+                    Err(err) => return Err(fail(err)),
+                }
+                reference.remove(key.clone());
+            }
+            Op::Reopen => {
+                drop(engine);
+                engine = E::open(temp_dir.path()).map_err(fail)?;
+            }
+            Op::Compact => engine.compact().map_err(fail)?,
+        }
+    }
+
+    drop(engine);
+    let engine = E::open(temp_dir.path()).map_err(fail)?;
+    for key_id in 0..KEYS {
+        let key = format!("key{}", key_id);
+        prop_assert_eq!(
+            engine.get(key.clone()).map_err(fail)?,
+            reference.get(key),
+            "after reopen"
+        );
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // KvStore should answer every operation the way a HashMap does, across
+    // segment rotations, compactions and reopens
+    #[test]
+    fn kv_store_matches_reference(ops in prop::collection::vec(op(), 1..100)) {
+        check_against_reference::<KvStore>(&ops)?;
+    }
+
+    // So should sled
+    #[test]
+    fn sled_matches_reference(ops in prop::collection::vec(op(), 1..100)) {
+        check_against_reference::<SledEngine>(&ops)?;
+    }
+}
@@ -0,0 +1,344 @@
+use kvs::protocol::{self, ErrorCode, Pipeline, Reply};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{serve_async, KvStore, KvsError, KvsServer, MPCommand, Result, WriteBatch};
+use slog::{o, Discard, Logger};
+use std::io::{BufReader, Cursor, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Starts a threaded server on a free port, returning its address
+fn start_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let server = KvsServer::builder()
+        .engine(KvStore::open(temp_dir.path())?)
+        .pool(SharedQueueThreadPool::new(2)?)
+        .addr("127.0.0.1:0".parse()?)
+        .build()?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.run());
+    Ok(addr)
+}
+
+/// Starts an async server on a free port, returning its address
+fn start_async_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let engine = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(serve_async(engine, listener, Logger::root(Discard, o!())))
+    });
+    Ok(addr)
+}
+
+fn ok(value: &str) -> Reply {
+    Reply::Ok(value.as_bytes().to_vec())
+}
+
+// Requests and replies should survive a round trip through the framing
+#[test]
+fn framing_round_trip() -> Result<()> {
+    let commands = vec![
+        MPCommand::Set {
+            key: b"key1".to_vec(),
+            value: b"value1".to_vec(),
+            expires_at: None,
+        },
+        MPCommand::Get {
+            key: b"key1".to_vec(),
+        },
+        MPCommand::Rm {
+            key: b"key1".to_vec(),
+        },
+        MPCommand::Set {
+            key: vec![0xff, 0x00],
+            value: vec![0xc3, 0x28, 0x00],
+            expires_at: Some(1_700_000_000_000),
+        },
+        MPCommand::SetWithTtl {
+            key: b"key2".to_vec(),
+            value: b"value2".to_vec(),
+            ttl_ms: 1000,
+        },
+        MPCommand::Expire {
+            key: b"key2".to_vec(),
+            ttl_ms: 500,
+        },
+        MPCommand::Ttl {
+            key: b"key2".to_vec(),
+        },
+        MPCommand::Persist {
+            key: b"key2".to_vec(),
+        },
+        MPCommand::MGet {
+            keys: vec![b"key1".to_vec(), vec![0xff, 0x00], vec![]],
+        },
+        MPCommand::MSet {
+            pairs: vec![
+                (b"key1".to_vec(), b"value1".to_vec()),
+                (vec![0xff, 0x00], vec![]),
+            ],
+        },
+    ];
+    let mut buf = vec![];
+    protocol::write_request(&mut buf, &commands)?;
+    protocol::write_request(&mut buf, &commands[1..])?;
+    let mut reader = Cursor::new(buf);
+    assert_eq!(protocol::read_request(&mut reader)?, Some(commands.clone()));
+    assert_eq!(
+        protocol::read_request(&mut reader)?,
+        Some(commands[1..].to_vec())
+    );
+    assert_eq!(protocol::read_request(&mut reader)?, None);
+
+    let replies = vec![
+        ok(""),
+        ok("value1"),
+        Reply::Nil,
+        Reply::Err(ErrorCode::KeyNotFound, "Key not found".to_owned()),
+        ok("Key not found"),
+    ];
+    let mut buf = vec![];
+    protocol::write_replies(&mut buf, &replies)?;
+    assert_eq!(protocol::read_replies(&mut Cursor::new(buf))?, replies);
+    Ok(())
+}
+
+// A request cut off part way through a command should be refused by both the
+// blocking and the async reader, not decoded from what arrived
+#[test]
+fn truncated_request() -> Result<()> {
+    let commands = vec![MPCommand::Set {
+        key: b"key1".to_vec(),
+        value: b"value1".to_vec(),
+        expires_at: None,
+    }];
+    let mut buf = vec![];
+    protocol::write_request(&mut buf, &commands)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    assert_eq!(
+        runtime.block_on(protocol::read_request_async(&mut &buf[..]))?,
+        Some(commands)
+    );
+
+    // the command claims more bytes than arrive before the connection closes,
+    // though the ones that do would decode on their own
+    let mut truncated = buf.clone();
+    // past the `*` and the number of commands
+    let len_at = 1 + 8;
+    let len = u64::from_be_bytes(truncated[len_at..len_at + 8].try_into().unwrap());
+    truncated[len_at..len_at + 8].copy_from_slice(&(len + 5).to_be_bytes());
+    assert!(protocol::read_request(&mut Cursor::new(&truncated)).is_err());
+    assert!(runtime
+        .block_on(protocol::read_request_async(&mut &truncated[..]))
+        .is_err());
+    Ok(())
+}
+
+// The entries of an MGet reply should keep their order and nil markers
+#[test]
+fn mget_entries_round_trip() -> Result<()> {
+    let entries = vec![
+        ok("value1"),
+        Reply::Nil,
+        ok(""),
+        Reply::Err(ErrorCode::ServerError, "disk full".to_owned()),
+        Reply::Nil,
+    ];
+    let encoded = protocol::encode_entries(&entries)?;
+    assert_eq!(protocol::decode_entries(&encoded)?, entries);
+    assert_eq!(
+        protocol::decode_entries(&protocol::encode_entries(&[])?)?,
+        vec![]
+    );
+
+    assert!(protocol::decode_entries(&encoded[..encoded.len() - 1]).is_err());
+    let mut trailing = encoded;
+    trailing.push(b'_');
+    assert!(matches!(
+        protocol::decode_entries(&trailing),
+        Err(KvsError::Protocol(_))
+    ));
+    Ok(())
+}
+
+// Error codes should survive the wire and map back to typed errors
+#[test]
+fn error_codes() -> Result<()> {
+    let replies = vec![
+        Reply::Err(ErrorCode::KeyNotFound, "Key not found".to_owned()),
+        Reply::Err(ErrorCode::InvalidRequest, "bad command".to_owned()),
+        Reply::Err(ErrorCode::ServerError, "disk full".to_owned()),
+        Reply::Err(ErrorCode::InvalidValue, "not an integer".to_owned()),
+    ];
+    let mut buf = vec![];
+    protocol::write_replies(&mut buf, &replies)?;
+    let mut replies = protocol::read_replies(&mut Cursor::new(buf))?.into_iter();
+
+    assert!(matches!(
+        replies.next().unwrap().into_result(),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        replies.next().unwrap().into_result(),
+        Err(KvsError::Protocol(msg)) if msg == "bad command"
+    ));
+    assert!(matches!(
+        replies.next().unwrap().into_result(),
+        Err(KvsError::Server(msg)) if msg == "disk full"
+    ));
+    assert!(matches!(
+        replies.next().unwrap().into_result(),
+        Err(KvsError::InvalidValue(msg)) if msg == "not an integer"
+    ));
+    Ok(())
+}
+
+fn pipelining(addr: SocketAddr) -> Result<()> {
+    let mut stream = TcpStream::connect(addr)?;
+
+    // every command of a batch gets exactly one reply, in order
+    let replies = Pipeline::new()
+        .set("key1".to_owned(), "value1".to_owned())
+        .set("key2".to_owned(), "value2".to_owned())
+        .get("key1".to_owned())
+        .remove("key3".to_owned())
+        .get("key2".to_owned())
+        .send(&mut stream)?;
+    assert_eq!(
+        replies,
+        vec![
+            ok(""),
+            ok(""),
+            ok("value1"),
+            Reply::Err(ErrorCode::KeyNotFound, "Key not found".to_owned()),
+            ok("value2"),
+        ]
+    );
+
+    // keys can be given a time to live
+    let replies = Pipeline::new()
+        .set_with_ttl("key4", "value4", Duration::from_secs(60))
+        .ttl("key4")
+        .persist("key4")
+        .ttl("key4")
+        .expire("key3", Duration::from_secs(60))
+        .send(&mut stream)?;
+    let ttl = protocol::decode_ttl(&replies[1].clone().into_result()?)?;
+    assert!(ttl.is_some_and(|ttl| ttl <= Duration::from_secs(60)));
+    assert_eq!(
+        replies,
+        vec![
+            ok(""),
+            replies[1].clone(),
+            ok(""),
+            ok(""),
+            Reply::Err(ErrorCode::KeyNotFound, "Key not found".to_owned()),
+        ]
+    );
+
+    // the writes of a batch are applied all-or-nothing
+    let mut failing = WriteBatch::new();
+    failing.set("key5", "value5").remove("missing");
+    let mut batch = WriteBatch::new();
+    batch.set("key6", "value6").remove("key4");
+    let replies = Pipeline::new()
+        .write_batch(&failing)
+        .get("key5")
+        .write_batch(&batch)
+        .get("key6")
+        .send(&mut stream)?;
+    let not_found = Reply::Err(ErrorCode::KeyNotFound, "Key not found".to_owned());
+    assert_eq!(
+        replies,
+        vec![
+            not_found.clone(),
+            not_found.clone(),
+            not_found.clone(),
+            not_found,
+            Reply::Nil,
+            ok(""),
+            ok(""),
+            ok(""),
+            ok(""),
+            ok("value6"),
+        ]
+    );
+    let replies = Pipeline::new()
+        .push(MPCommand::BatchBegin { len: 1 })
+        .set("key7", "value7")
+        .get("key7")
+        .send(&mut stream)?;
+    assert!(matches!(
+        replies[0],
+        Reply::Err(ErrorCode::InvalidRequest, _)
+    ));
+    assert!(matches!(
+        replies[1],
+        Reply::Err(ErrorCode::InvalidRequest, _)
+    ));
+    assert_eq!(replies[2], Reply::Nil);
+
+    // a missing key is nil, unlike a value that happens to read "Key not found"
+    let replies = Pipeline::new()
+        .set("key7", "Key not found")
+        .get("key7")
+        .get("missing")
+        .send(&mut stream)?;
+    assert_eq!(replies, vec![ok(""), ok("Key not found"), Reply::Nil]);
+    assert_eq!(
+        replies[1].clone().into_value()?,
+        Some(b"Key not found".to_vec())
+    );
+    assert_eq!(replies[2].clone().into_value()?, None);
+    assert!(matches!(
+        replies[2].clone().into_result(),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // several keys can be fetched and set in one command
+    let replies = Pipeline::new()
+        .mset([("key8", "value8"), ("key9", "value9")])
+        .mget(["key8", "missing", "key9"])
+        .send(&mut stream)?;
+    assert_eq!(replies[0], ok(""));
+    assert_eq!(
+        protocol::decode_entries(&replies[1].clone().into_result()?)?,
+        vec![ok("value8"), Reply::Nil, ok("value9")]
+    );
+
+    // the connection stays open for further batches
+    for i in 0..10 {
+        let replies = Pipeline::new()
+            .set("key1".to_owned(), format!("value{}", i))
+            .get("key1".to_owned())
+            .send(&mut stream)?;
+        assert_eq!(replies, vec![ok(""), ok(&format!("value{}", i))]);
+    }
+
+    // several batches may be sent before reading any replies
+    let mut batch = Pipeline::new();
+    batch.get("key2".to_owned());
+    for _ in 0..3 {
+        protocol::write_request(&mut stream, batch.commands())?;
+    }
+    stream.flush()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    for _ in 0..3 {
+        assert_eq!(protocol::read_replies(&mut reader)?, vec![ok("value2")]);
+    }
+    Ok(())
+}
+
+#[test]
+fn pipelining_threaded_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    pipelining(start_server(&temp_dir)?)
+}
+
+#[test]
+fn pipelining_async_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    pipelining(start_async_server(&temp_dir)?)
+}
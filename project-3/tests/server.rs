@@ -0,0 +1,126 @@
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Result, SledEngine};
+use std::sync::Arc;
+use std::thread;
+use tempfile::TempDir;
+
+fn serve_and_shut_down<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    engine: E,
+    pool: P,
+) -> Result<()> {
+    let server = KvsServer::builder()
+        .engine(engine)
+        .pool(pool)
+        .addr("127.0.0.1:0".parse()?)
+        .build()?;
+    let addr = server.local_addr()?;
+    let server = Arc::new(server);
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run())
+    };
+
+    // clients on separate connections are served at the same time
+    let mut clients = (0..3)
+        .map(|_| KvsClient::connect(addr))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for client in clients.iter_mut() {
+        for i in 0..3 {
+            assert_eq!(
+                client.get(format!("key{}", i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    server.shutdown()?;
+    handle.join().expect("server thread panicked")?;
+    // connections that were open before the shutdown are still served
+    assert_eq!(
+        clients[0].get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn kvs_engine_shared_queue_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    serve_and_shut_down(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+}
+
+#[test]
+fn kvs_engine_naive_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    serve_and_shut_down(KvStore::open(temp_dir.path())?, NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn sled_engine_rayon_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    serve_and_shut_down(SledEngine::open(temp_dir.path())?, RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn builder_needs_engine_and_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let no_engine = KvsServer::<KvStore, _>::builder()
+        .pool(NaiveThreadPool::new(1)?)
+        .addr("127.0.0.1:0".parse()?)
+        .build();
+    assert!(no_engine.is_err());
+
+    let no_pool = KvsServer::<_, NaiveThreadPool>::builder()
+        .engine(KvStore::open(temp_dir.path())?)
+        .addr("127.0.0.1:0".parse()?)
+        .build();
+    assert!(no_pool.is_err());
+    Ok(())
+}
+
+// A scan whose end comes before its start should get an empty reply and
+// leave the server running
+fn reversed_scan<E: KvsEngine>(engine: E) -> Result<()> {
+    let server = KvsServer::builder()
+        .engine(engine)
+        .pool(NaiveThreadPool::new(2)?)
+        .addr("127.0.0.1:0".parse()?)
+        .build()?;
+    let addr = server.local_addr()?;
+    let server = Arc::new(server);
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run())
+    };
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("a".to_owned(), "A".to_owned())?;
+    client.set("b".to_owned(), "B".to_owned())?;
+    assert_eq!(client.scan(b"b", Some(b"a"), None)?, vec![]);
+    assert_eq!(client.scan(b"b", Some(b"b"), None)?, vec![]);
+    assert_eq!(
+        client.scan(b"a", Some(b"b"), None)?,
+        vec![(b"a".to_vec(), b"A".to_vec())]
+    );
+
+    server.shutdown()?;
+    handle.join().expect("server thread panicked")
+}
+
+#[test]
+fn reversed_scan_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    reversed_scan(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn reversed_scan_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    reversed_scan(SledEngine::open(temp_dir.path())?)
+}
@@ -0,0 +1,103 @@
+use crossbeam_utils::sync::WaitGroup;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvsError, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier};
+use std::time::Duration;
+
+const THREADS: u32 = 4;
+
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    const TASK_NUM: usize = 20;
+    const ADD_COUNT: usize = 1000;
+
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            for _ in 0..ADD_COUNT {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            drop(wg);
+        })
+    }
+
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * ADD_COUNT);
+    Ok(())
+}
+
+// Panics every thread of the pool, then checks that the pool can still run
+// THREADS jobs at the same time
+fn panic_task<P: ThreadPool>(pool: P) -> Result<()> {
+    let wg = WaitGroup::new();
+    for _ in 0..THREADS {
+        let wg = wg.clone();
+        pool.spawn(move || {
+            // the wait group is released while the panic unwinds
+            let _wg = wg;
+            panic!("job panicked on purpose");
+        })
+    }
+    wg.wait();
+
+    // every job blocks until all of them are running, which can only happen
+    // if the pool still has all of its threads
+    let barrier = Arc::new(Barrier::new(THREADS as usize));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..THREADS {
+        let barrier = Arc::clone(&barrier);
+        let sender = sender.clone();
+        pool.spawn(move || {
+            barrier.wait();
+            sender.send(()).unwrap();
+        })
+    }
+    for _ in 0..THREADS {
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("pool lost threads to panicking jobs");
+    }
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(THREADS)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(THREADS)?)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(THREADS)?)
+}
+
+#[test]
+fn naive_thread_pool_panic_task() -> Result<()> {
+    panic_task(NaiveThreadPool::new(THREADS)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    panic_task(SharedQueueThreadPool::new(THREADS)?)
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    panic_task(RayonThreadPool::new(THREADS)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_needs_threads() {
+    assert!(matches!(
+        SharedQueueThreadPool::new(0),
+        Err(KvsError::InvalidConfig(_))
+    ));
+}